@@ -0,0 +1,143 @@
+use crate::checkpoint::Checkpoint;
+use crate::source::{Shard, StateSource};
+use crate::types::{AbciState, DbAccountInfo, EvmDb};
+use alloy_primitives::{Address, Bytes, B256, U256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Decode an `abci_state.rmp` snapshot.
+pub fn read_abci_state(path: &Path) -> eyre::Result<AbciState> {
+    let file = File::open(path)?;
+    let mut reader = std::io::BufReader::new(file);
+    Ok(rmp_serde::decode::from_read(&mut reader)?)
+}
+
+/// An account of `EvmDb::InMemory` with its non-zero storage.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryAccount {
+    pub info: DbAccountInfo,
+    pub storage: BTreeMap<B256, U256>,
+}
+
+/// `EvmDb::InMemory` re-indexed by address and code hash.
+#[derive(Debug, Clone, Default)]
+pub struct InMemorySource {
+    pub accounts: BTreeMap<Address, InMemoryAccount>,
+    pub contracts: BTreeMap<B256, Bytes>,
+}
+
+impl StateSource for InMemorySource {
+    fn accounts(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<(Address, DbAccountInfo)>> + '_> {
+        Box::new(
+            self.accounts
+                .range(shard.start_address()..)
+                .take_while(move |(address, _)| shard.contains(address.as_slice()))
+                .map(|(address, account)| Ok((*address, account.info.clone()))),
+        )
+    }
+
+    fn account(&self, address: Address) -> eyre::Result<Option<DbAccountInfo>> {
+        Ok(self.accounts.get(&address).map(|account| account.info.clone()))
+    }
+
+    fn storage(&self, address: Address) -> eyre::Result<BTreeMap<B256, U256>> {
+        Ok(self
+            .accounts
+            .get(&address)
+            .map(|account| account.storage.clone())
+            .unwrap_or_default())
+    }
+
+    fn storage_entries(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<((Address, B256), U256)>> + '_> {
+        let accounts = self
+            .accounts
+            .range(shard.start_address()..)
+            .take_while(move |(address, _)| shard.contains(address.as_slice()));
+        Box::new(accounts.flat_map(|(address, account)| {
+            account
+                .storage
+                .iter()
+                .map(move |(slot, value)| Ok(((*address, *slot), *value)))
+        }))
+    }
+
+    fn bytecode(&self, code_hash: B256) -> eyre::Result<Option<Bytes>> {
+        Ok(self.contracts.get(&code_hash).cloned())
+    }
+
+    fn contracts(&self, shard: Shard) -> Box<dyn Iterator<Item = eyre::Result<(B256, Bytes)>> + '_> {
+        Box::new(
+            self.contracts
+                .range(shard.start_hash()..)
+                .take_while(move |(code_hash, _)| shard.contains(code_hash.as_slice()))
+                .map(|(code_hash, code)| Ok((*code_hash, code.clone()))),
+        )
+    }
+}
+
+#[cfg(test)]
+impl InMemorySource {
+    /// Add an account with the given non-zero slots.
+    pub(crate) fn with_account(
+        mut self,
+        address: Address,
+        info: DbAccountInfo,
+        storage: &[(B256, U256)],
+    ) -> Self {
+        let storage = storage.iter().copied().collect();
+        self.accounts.insert(address, InMemoryAccount { info, storage });
+        self
+    }
+
+    /// Add `code` under its hash.
+    pub(crate) fn with_contract(mut self, code: Bytes) -> Self {
+        let code_hash = alloy_primitives::keccak256(&code);
+        self.contracts.insert(code_hash, code);
+        self
+    }
+}
+
+/// Open the state behind a decoded `EvmDb`. `checkpoint` is only called to locate the RocksDB
+/// checkpoint for `EvmDb::NoEvmDb`.
+pub fn open_evm_db(
+    evm_db: EvmDb,
+    checkpoint: impl FnOnce() -> eyre::Result<PathBuf>,
+) -> eyre::Result<Box<dyn StateSource + Send + Sync>> {
+    match evm_db {
+        EvmDb::InMemory {
+            accounts,
+            contracts,
+        } => Ok(Box::new(InMemorySource {
+            accounts: accounts
+                .into_iter()
+                .map(|(address, account)| {
+                    let storage = account
+                        .storage
+                        .into_iter()
+                        .filter(|(_, value)| *value != U256::ZERO)
+                        .map(|(slot, value)| (slot.into(), value))
+                        .collect();
+                    (
+                        address,
+                        InMemoryAccount {
+                            info: account.info,
+                            storage,
+                        },
+                    )
+                })
+                .collect(),
+            contracts: contracts
+                .into_iter()
+                .map(|(code_hash, code)| (code_hash, code.original_bytes()))
+                .collect(),
+        })),
+        EvmDb::NoEvmDb {} => Ok(Box::new(Checkpoint::open(&checkpoint()?)?)),
+    }
+}
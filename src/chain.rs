@@ -0,0 +1,84 @@
+use crate::diff::merge_join;
+use crate::report::Divergence;
+use alloy_consensus::BlockHeader;
+use alloy_primitives::B256;
+use reth_primitives::SealedHeader;
+use std::collections::BTreeMap;
+
+/// Compare two sealed headers field by field, including the seal hash.
+pub fn diff_headers<L: BlockHeader, R: BlockHeader>(
+    left: &SealedHeader<L>,
+    right: &SealedHeader<R>,
+) -> Vec<Divergence> {
+    let number = left.header().number();
+    let mut divergences = Vec::new();
+    let mut compare = |field: &'static str, l: String, r: String| {
+        if l != r {
+            divergences.push(Divergence::Header {
+                number,
+                field,
+                left: l,
+                right: r,
+            });
+        }
+    };
+    compare("hash", format!("{:?}", left.hash()), format!("{:?}", right.hash()));
+
+    let (left, right) = (left.header(), right.header());
+    macro_rules! compare_fields {
+        ($($field:ident),* $(,)?) => {
+            $(
+                compare(
+                    stringify!($field),
+                    format!("{:?}", left.$field()),
+                    format!("{:?}", right.$field()),
+                );
+            )*
+        };
+    }
+    compare_fields!(
+        parent_hash,
+        ommers_hash,
+        beneficiary,
+        state_root,
+        transactions_root,
+        receipts_root,
+        withdrawals_root,
+        logs_bloom,
+        difficulty,
+        number,
+        gas_limit,
+        gas_used,
+        timestamp,
+        mix_hash,
+        nonce,
+        base_fee_per_gas,
+        blob_gas_used,
+        excess_blob_gas,
+        parent_beacon_block_root,
+        requests_hash,
+        extra_data,
+    );
+    divergences
+}
+
+/// Compare two `(number, hash)` lists, reporting numbers missing on either side.
+pub fn diff_block_hashes(
+    left: impl IntoIterator<Item = (u64, B256)>,
+    right: impl IntoIterator<Item = (u64, B256)>,
+) -> eyre::Result<Vec<Divergence>> {
+    let left: BTreeMap<u64, B256> = left.into_iter().collect();
+    let right: BTreeMap<u64, B256> = right.into_iter().collect();
+    let mut divergences = Vec::new();
+    for entry in merge_join(left.into_iter().map(Ok), right.into_iter().map(Ok)) {
+        let (number, left, right) = entry?;
+        if left != right {
+            divergences.push(Divergence::BlockHash {
+                number,
+                left,
+                right,
+            });
+        }
+    }
+    Ok(divergences)
+}
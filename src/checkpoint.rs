@@ -0,0 +1,347 @@
+use crate::source::{Shard, StateSource};
+use crate::types::{Bytecode, DbAccountInfo};
+use alloy_primitives::{Address, Bytes, B256, U256};
+use rocksdb::{Direction, IteratorMode, Options, ReadOptions, WriteBatch, DB};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Key prefix of account entries: `Ea` + address.
+pub const ACCOUNT_PREFIX: &[u8; 2] = b"\x45\x61";
+/// Key prefix of storage entries: `Es` + address + slot.
+pub const STORAGE_PREFIX: &[u8; 2] = b"\x45\x73";
+/// Key prefix of bytecode entries: `Ec` + code hash.
+pub const CONTRACT_PREFIX: &[u8; 2] = b"\x45\x63";
+
+/// The RocksDB checkpoint backing `EvmDb::NoEvmDb`. All values are msgpack encoded.
+pub struct Checkpoint {
+    db: DB,
+}
+
+/// Default location of the evm db relative to `$HOME` on a node host.
+pub const DEFAULT_EVM_DB_ROOT: &str = "hl/hyperliquid_data/evm_db_hub_slow";
+
+/// `$HOME/hl/hyperliquid_data/evm_db_hub_slow`.
+pub fn default_evm_db_root() -> eyre::Result<PathBuf> {
+    let home = std::env::var("HOME")?;
+    Ok(PathBuf::from(home).join(DEFAULT_EVM_DB_ROOT))
+}
+
+/// Locate the `EvmState` checkpoint for `height` under `root/checkpoint/<height>`.
+///
+/// If there is no checkpoint at exactly `height`, the nearest one below it is used, or the
+/// nearest one above it if there is none below, and its height returned so callers can warn
+/// about the mismatch.
+pub fn find_checkpoint(root: &Path, height: u64) -> eyre::Result<(u64, PathBuf)> {
+    let checkpoints = root.join("checkpoint");
+    let exact = checkpoints.join(height.to_string()).join("EvmState");
+    if exact.is_dir() {
+        return Ok((height, exact));
+    }
+
+    let entries = std::fs::read_dir(&checkpoints).map_err(|e| {
+        eyre::eyre!(
+            "cannot list checkpoints in {}: {e}; pass --evm-db-root or --checkpoint-dir",
+            checkpoints.display()
+        )
+    })?;
+    let mut nearest: Option<u64> = None;
+    for entry in entries {
+        let entry = entry?;
+        let Some(candidate) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        if !entry.path().join("EvmState").is_dir() {
+            continue;
+        }
+        // Earlier states rank before later ones, then by distance, so the choice doesn't
+        // depend on the directory listing order.
+        let rank = |checkpoint: u64| (checkpoint > height, checkpoint.abs_diff(height));
+        if nearest.is_none_or(|nearest| rank(candidate) < rank(nearest)) {
+            nearest = Some(candidate);
+        }
+    }
+    match nearest {
+        Some(nearest) => Ok((
+            nearest,
+            checkpoints.join(nearest.to_string()).join("EvmState"),
+        )),
+        None => eyre::bail!(
+            "no checkpoint found in {}; pass --evm-db-root or --checkpoint-dir",
+            checkpoints.display()
+        ),
+    }
+}
+
+/// Number of entries of each kind written by [`write_checkpoint`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckpointCounts {
+    pub accounts: u64,
+    pub slots: u64,
+    pub contracts: u64,
+}
+
+/// Entries buffered before a write batch is flushed to RocksDB.
+const BATCH_SIZE: usize = 100_000;
+
+/// Write every account, non-zero slot and bytecode of `source` into a new RocksDB at `path`,
+/// using the same key layout and msgpack values that [`Checkpoint`] reads.
+///
+/// `progress` is called with the running counts each time a batch is written. Fails if a
+/// database already exists at `path`.
+pub fn write_checkpoint(
+    source: &dyn StateSource,
+    path: &Path,
+    mut progress: impl FnMut(&CheckpointCounts),
+) -> eyre::Result<CheckpointCounts> {
+    let mut opts = Options::default();
+    opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(2));
+    opts.create_if_missing(true);
+    opts.set_error_if_exists(true);
+    let db = DB::open(&opts, path)?;
+
+    let mut counts = CheckpointCounts::default();
+    let mut batch = WriteBatch::default();
+    let mut flush = |batch: &mut WriteBatch, counts: &CheckpointCounts, all: bool| {
+        if all || batch.len() >= BATCH_SIZE {
+            db.write(std::mem::take(batch))?;
+            progress(counts);
+        }
+        eyre::Ok(())
+    };
+
+    for account in source.accounts(Shard::ALL) {
+        let (address, info) = account?;
+        let key = [ACCOUNT_PREFIX.as_slice(), address.as_slice()].concat();
+        batch.put(key, rmp_serde::to_vec_named(&info)?);
+        counts.accounts += 1;
+        flush(&mut batch, &counts, false)?;
+    }
+    for entry in source.storage_entries(Shard::ALL) {
+        let ((address, slot), value) = entry?;
+        let key = [STORAGE_PREFIX.as_slice(), address.as_slice(), slot.as_slice()].concat();
+        batch.put(key, rmp_serde::to_vec_named(&B256::from(value))?);
+        counts.slots += 1;
+        flush(&mut batch, &counts, false)?;
+    }
+    for contract in source.contracts(Shard::ALL) {
+        let (code_hash, code) = contract?;
+        let key = [CONTRACT_PREFIX.as_slice(), code_hash.as_slice()].concat();
+        batch.put(key, rmp_serde::to_vec_named(&Bytecode::new_raw(code))?);
+        counts.contracts += 1;
+        flush(&mut batch, &counts, false)?;
+    }
+    flush(&mut batch, &counts, true)?;
+    db.flush()?;
+    Ok(counts)
+}
+
+impl Checkpoint {
+    pub fn open(path: &Path) -> eyre::Result<Self> {
+        if !path.is_dir() {
+            eyre::bail!("checkpoint directory {} does not exist", path.display());
+        }
+        let prefix_extractor = rocksdb::SliceTransform::create_fixed_prefix(2);
+        let mut opts = Options::default();
+        opts.set_prefix_extractor(prefix_extractor);
+        let db = DB::open_for_read_only(&opts, path, false)?;
+        Ok(Self { db })
+    }
+
+    /// Iterate all entries whose key starts with `prefix` and whose remainder falls in
+    /// `shard`. The DB's prefix extractor only covers the two byte tag, so longer prefixes are
+    /// checked here.
+    fn scan(
+        &self,
+        prefix: &[u8],
+        shard: Shard,
+    ) -> impl Iterator<Item = eyre::Result<(Box<[u8]>, Box<[u8]>)>> + '_ {
+        let prefix = prefix.to_vec();
+        let start = [prefix.as_slice(), &[shard.start_byte()]].concat();
+        let mut opts = ReadOptions::default();
+        opts.set_prefix_same_as_start(true);
+        self.db
+            .iterator_opt(IteratorMode::From(&start, Direction::Forward), opts)
+            .take_while(move |entry| match entry {
+                Ok((key, _)) => key.starts_with(&prefix) && shard.contains(&key[prefix.len()..]),
+                Err(_) => true,
+            })
+            .map(|entry| entry.map_err(Into::into))
+    }
+}
+
+impl StateSource for Checkpoint {
+    fn accounts(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<(Address, DbAccountInfo)>> + '_> {
+        Box::new(self.scan(ACCOUNT_PREFIX, shard).map(|entry| -> eyre::Result<_> {
+            let (key, value) = entry?;
+            let address = Address::from_slice(&key[2..22]);
+            let info: DbAccountInfo = rmp_serde::from_slice(&value)?;
+            Ok((address, info))
+        }))
+    }
+
+    fn account(&self, address: Address) -> eyre::Result<Option<DbAccountInfo>> {
+        let key = [ACCOUNT_PREFIX.as_slice(), address.as_slice()].concat();
+        match self.db.get_pinned(key)? {
+            Some(value) => Ok(Some(rmp_serde::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn storage(&self, address: Address) -> eyre::Result<BTreeMap<B256, U256>> {
+        let prefix = [STORAGE_PREFIX.as_slice(), address.as_slice()].concat();
+        let mut storage = BTreeMap::new();
+        for entry in self.scan(&prefix, Shard::ALL) {
+            let (key, value) = entry?;
+            let storage_key = B256::from_slice(&key[22..54]);
+            let storage_value: B256 = rmp_serde::from_slice(&value)?;
+            let storage_value: U256 = storage_value.into();
+            if storage_value != U256::ZERO {
+                storage.insert(storage_key, storage_value);
+            }
+        }
+        Ok(storage)
+    }
+
+    fn storage_entries(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<((Address, B256), U256)>> + '_> {
+        Box::new(
+            self.scan(STORAGE_PREFIX, shard)
+                .map(|entry| -> eyre::Result<_> {
+                    let (key, value) = entry?;
+                    let address = Address::from_slice(&key[2..22]);
+                    let storage_key = B256::from_slice(&key[22..54]);
+                    let storage_value: B256 = rmp_serde::from_slice(&value)?;
+                    Ok(((address, storage_key), storage_value.into()))
+                })
+                .filter(|entry| !matches!(entry, Ok((_, value)) if *value == U256::ZERO)),
+        )
+    }
+
+    fn bytecode(&self, code_hash: B256) -> eyre::Result<Option<Bytes>> {
+        let key = [CONTRACT_PREFIX.as_slice(), code_hash.as_slice()].concat();
+        match self.db.get_pinned(key)? {
+            Some(value) => Ok(Some(rmp_serde::from_slice::<Bytecode>(&value)?.original_bytes())),
+            None => Ok(None),
+        }
+    }
+
+    fn contracts(&self, shard: Shard) -> Box<dyn Iterator<Item = eyre::Result<(B256, Bytes)>> + '_> {
+        Box::new(self.scan(CONTRACT_PREFIX, shard).map(|entry| -> eyre::Result<_> {
+            let (key, value) = entry?;
+            let code_hash = B256::from_slice(&key[2..34]);
+            let bytecode: Bytecode = rmp_serde::from_slice(&value)?;
+            Ok((code_hash, bytecode.original_bytes()))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abci::InMemorySource;
+    use crate::diff::StateDiffer;
+    use alloy_consensus::constants::KECCAK_EMPTY;
+
+    /// A fresh path under the system temp directory, removed again on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("evm-diff-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn finds_nearest_checkpoint_at_or_below_height() {
+        let root = TempDir::new("find-checkpoint");
+        let add = |height: u64| {
+            let path = root.0.join("checkpoint").join(height.to_string());
+            std::fs::create_dir_all(path.join("EvmState")).unwrap();
+        };
+        add(12);
+        add(11);
+        assert_eq!(find_checkpoint(&root.0, 10).unwrap().0, 11);
+        add(9);
+        assert_eq!(find_checkpoint(&root.0, 10).unwrap().0, 9);
+        add(8);
+        assert_eq!(find_checkpoint(&root.0, 10).unwrap().0, 9);
+        add(10);
+        assert_eq!(find_checkpoint(&root.0, 10).unwrap().0, 10);
+    }
+
+    #[test]
+    fn written_checkpoint_reads_back() {
+        let legacy = Bytes::from_static(&[0x60, 0x01, 0x60, 0x00, 0x55]);
+        let delegation = Bytes::from([&[0xef, 0x01, 0x00][..], &[0x11; 20][..]].concat());
+        let contract = DbAccountInfo {
+            balance: U256::from(5),
+            nonce: 1,
+            code_hash: alloy_primitives::keccak256(&legacy),
+        };
+        let eoa = DbAccountInfo {
+            balance: U256::from(u64::MAX) << 100,
+            nonce: 42,
+            code_hash: KECCAK_EMPTY,
+        };
+        let source = InMemorySource::default()
+            .with_account(
+                Address::repeat_byte(0x01),
+                contract.clone(),
+                &[
+                    (B256::with_last_byte(1), U256::from(1)),
+                    (B256::repeat_byte(0xff), U256::MAX),
+                ],
+            )
+            .with_account(Address::repeat_byte(0xfe), eoa, &[])
+            .with_contract(legacy.clone())
+            .with_contract(delegation.clone());
+
+        let dir = TempDir::new("checkpoint-round-trip");
+        let mut flushes = 0;
+        let counts = write_checkpoint(&source, &dir.0, |_| flushes += 1).unwrap();
+        assert_eq!((counts.accounts, counts.slots, counts.contracts), (2, 2, 2));
+        assert_eq!(flushes, 1);
+
+        let checkpoint = Checkpoint::open(&dir.0).unwrap();
+        let divergences: Vec<_> = StateDiffer::new(&source, &checkpoint)
+            .divergences()
+            .collect::<eyre::Result<_>>()
+            .unwrap();
+        assert!(divergences.is_empty(), "{divergences:?}");
+        assert_eq!(
+            checkpoint.account(Address::repeat_byte(0x01)).unwrap(),
+            Some(contract)
+        );
+        assert_eq!(
+            checkpoint
+                .bytecode(alloy_primitives::keccak256(&delegation))
+                .unwrap(),
+            Some(delegation)
+        );
+    }
+
+    #[test]
+    fn refuses_to_overwrite_a_database() {
+        let dir = TempDir::new("checkpoint-exists");
+        write_checkpoint(&InMemorySource::default(), &dir.0, |_| {}).unwrap();
+        assert!(write_checkpoint(&InMemorySource::default(), &dir.0, |_| {}).is_err());
+    }
+}
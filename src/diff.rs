@@ -0,0 +1,782 @@
+use crate::source::{Shard, StateSource};
+use crate::types::DbAccountInfo;
+use alloy_consensus::constants::KECCAK_EMPTY;
+use alloy_primitives::{Address, Bytes, B256, U256};
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt;
+use std::iter::Peekable;
+use std::ops::Add;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+pub use crate::report::Divergence;
+
+/// Join two key-ordered streams, yielding every key with its value on each side.
+pub fn merge_join<K: Ord, A, B>(
+    left: impl Iterator<Item = eyre::Result<(K, A)>>,
+    right: impl Iterator<Item = eyre::Result<(K, B)>>,
+) -> impl Iterator<Item = eyre::Result<(K, Option<A>, Option<B>)>> {
+    let mut left = left.peekable();
+    let mut right = right.peekable();
+    std::iter::from_fn(move || {
+        if let Some(Err(_)) = left.peek() {
+            return left.next().map(|entry| entry.map(|_| unreachable!()));
+        }
+        if let Some(Err(_)) = right.peek() {
+            return right.next().map(|entry| entry.map(|_| unreachable!()));
+        }
+        let ordering = match (left.peek(), right.peek()) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(Ok((l, _))), Some(Ok((r, _)))) => l.cmp(r),
+            _ => unreachable!(),
+        };
+        let joined = match ordering {
+            Ordering::Less => left.next()?.map(|(key, l)| (key, Some(l), None)),
+            Ordering::Greater => right.next()?.map(|(key, r)| (key, None, Some(r))),
+            Ordering::Equal => {
+                let r = right.next()?;
+                left.next()?
+                    .and_then(|(key, l)| r.map(|(_, r)| (key, Some(l), Some(r))))
+            }
+        };
+        Some(joined)
+    })
+}
+
+type Joined<'a, K, V> = Box<dyn Iterator<Item = eyre::Result<(K, Option<V>, Option<V>)>> + 'a>;
+
+/// Number of entries compared so far and the time it took.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Throughput {
+    pub accounts: u64,
+    pub slots: u64,
+    pub contracts: u64,
+    pub elapsed: Duration,
+}
+
+impl Add for Throughput {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            accounts: self.accounts + other.accounts,
+            slots: self.slots + other.slots,
+            contracts: self.contracts + other.contracts,
+            elapsed: self.elapsed.max(other.elapsed),
+        }
+    }
+}
+
+impl fmt::Display for Throughput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        write!(
+            f,
+            "compared {} accounts, {} slots and {} bytecodes in {:.1}s ({:.0} accounts/s, {:.0} slots/s)",
+            self.accounts,
+            self.slots,
+            self.contracts,
+            self.elapsed.as_secs_f64(),
+            self.accounts as f64 / secs,
+            self.slots as f64 / secs,
+        )
+    }
+}
+
+/// Compares two [`StateSource`]s and yields every [`Divergence`] found.
+///
+/// Accounts, storage and bytecodes of both sides are merge-joined in a single sequential pass
+/// over their key-ordered streams, so entries present on only one side are reported in either
+/// direction and no per-account lookups are needed.
+pub struct StateDiffer<'a> {
+    left: &'a dyn StateSource,
+    right: &'a dyn StateSource,
+    progress: bool,
+    storage_roots: bool,
+    throughput: Cell<Throughput>,
+    started: Cell<Option<Instant>>,
+}
+
+impl<'a> StateDiffer<'a> {
+    pub fn new(left: &'a dyn StateSource, right: &'a dyn StateSource) -> Self {
+        Self {
+            left,
+            right,
+            progress: false,
+            storage_roots: false,
+            throughput: Cell::default(),
+            started: Cell::default(),
+        }
+    }
+
+    /// Show a progress bar on stderr while walking accounts and contracts.
+    pub fn with_progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Compare the storage roots of accounts present on both sides and only diff the slots of
+    /// those whose roots differ, instead of streaming every slot.
+    ///
+    /// Slots of addresses without an account on either side are not checked in this mode.
+    pub fn with_storage_roots(mut self, storage_roots: bool) -> Self {
+        self.storage_roots = storage_roots;
+        self
+    }
+
+    /// Counters for the entries compared by [`Self::divergences`] so far.
+    pub fn throughput(&self) -> Throughput {
+        Throughput {
+            elapsed: self
+                .started
+                .get()
+                .map(|started| started.elapsed())
+                .unwrap_or_default(),
+            ..self.throughput.get()
+        }
+    }
+
+    /// Stream all divergences: accounts with their storage first, then bytecodes.
+    pub fn divergences(&self) -> Divergences<'_> {
+        self.divergences_in(Shard::ALL)
+    }
+
+    /// Stream the divergences of one [`Shard`] of the key space: its accounts with their
+    /// storage first, then its bytecodes.
+    pub fn divergences_in(&self, shard: Shard) -> Divergences<'_> {
+        Divergences {
+            contracts: self.contract_divergences_in(shard).contracts,
+            ..self.account_divergences_in(shard)
+        }
+    }
+
+    /// Stream the account and storage divergences of one [`Shard`], without its bytecodes.
+    pub fn account_divergences_in(&self, shard: Shard) -> Divergences<'_> {
+        self.started.set(Some(Instant::now()));
+        let accounts: Joined<'_, _, _> = Box::new(merge_join(
+            self.left.accounts(shard),
+            self.right.accounts(shard),
+        ));
+        let accounts: Joined<'_, _, _> = if self.progress {
+            Box::new(tqdm::tqdm(accounts))
+        } else {
+            accounts
+        };
+        let storage: Joined<'_, _, _> = if self.storage_roots {
+            Box::new(std::iter::empty())
+        } else {
+            Box::new(merge_join(
+                self.left.storage_entries(shard),
+                self.right.storage_entries(shard),
+            ))
+        };
+        Divergences {
+            accounts,
+            storage: storage.peekable(),
+            contracts: Box::new(std::iter::empty()),
+            storage_roots: self.storage_roots.then_some((self.left, self.right)),
+            pending: VecDeque::new(),
+            throughput: &self.throughput,
+        }
+    }
+
+    /// Stream the bytecode divergences of one [`Shard`] of the code hash space.
+    pub fn contract_divergences_in(&self, shard: Shard) -> Divergences<'_> {
+        self.started.set(Some(Instant::now()));
+        let contracts: Joined<'_, _, _> = Box::new(merge_join(
+            self.left.contracts(shard),
+            self.right.contracts(shard),
+        ));
+        let contracts: Joined<'_, _, _> = if self.progress {
+            Box::new(tqdm::tqdm(contracts))
+        } else {
+            contracts
+        };
+        let storage: Joined<'_, _, _> = Box::new(std::iter::empty());
+        Divergences {
+            accounts: Box::new(std::iter::empty()),
+            storage: storage.peekable(),
+            contracts,
+            storage_roots: None,
+            pending: VecDeque::new(),
+            throughput: &self.throughput,
+        }
+    }
+
+    /// Compare the fields of account `address`, given its info on each side (`None` if absent).
+    pub fn diff_account(
+        address: Address,
+        left: Option<&DbAccountInfo>,
+        right: Option<&DbAccountInfo>,
+    ) -> Vec<Divergence> {
+        let mut divergences = Vec::new();
+        let (left, right) = match (left, right) {
+            (Some(left), Some(right)) => (left, right),
+            (Some(left), None) => {
+                if !left.is_empty() {
+                    divergences.push(Divergence::ExtraAccount {
+                        address,
+                        balance: left.balance,
+                        nonce: left.nonce,
+                        code_hash: left.code_hash,
+                    });
+                }
+                return divergences;
+            }
+            (None, Some(right)) => {
+                if !right.is_empty() {
+                    divergences.push(Divergence::MissingAccount {
+                        address,
+                        balance: right.balance,
+                        nonce: right.nonce,
+                        code_hash: right.code_hash,
+                    });
+                }
+                return divergences;
+            }
+            (None, None) => return divergences,
+        };
+
+        if left.balance != right.balance {
+            divergences.push(Divergence::Balance {
+                address,
+                left: left.balance,
+                right: right.balance,
+            });
+        }
+        if left.nonce != right.nonce {
+            divergences.push(Divergence::Nonce {
+                address,
+                left: left.nonce,
+                right: right.nonce,
+            });
+        }
+        if left.code_hash != right.code_hash {
+            divergences.push(Divergence::CodeHash {
+                address,
+                left: left.code_hash,
+                right: right.code_hash,
+            });
+        }
+        divergences
+    }
+
+    /// Compare the bytecode stored under `code_hash`, given its bytes on each side.
+    pub fn diff_contract(
+        code_hash: B256,
+        left: Option<&Bytes>,
+        right: Option<&Bytes>,
+    ) -> Option<Divergence> {
+        if code_hash == KECCAK_EMPTY {
+            return None;
+        }
+        match (left, right) {
+            (Some(left), Some(right)) => {
+                (left != right).then_some(Divergence::Bytecode { code_hash })
+            }
+            (Some(_), None) => Some(Divergence::ExtraCode { code_hash }),
+            (None, Some(_)) => Some(Divergence::MissingCode { code_hash }),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Iterator returned by [`StateDiffer::divergences`].
+pub struct Divergences<'a> {
+    accounts: Joined<'a, Address, DbAccountInfo>,
+    storage: Peekable<Joined<'a, (Address, B256), U256>>,
+    contracts: Joined<'a, B256, Bytes>,
+    /// Both sources, when storage is compared by root rather than streamed.
+    storage_roots: Option<(&'a dyn StateSource, &'a dyn StateSource)>,
+    pending: VecDeque<eyre::Result<Divergence>>,
+    throughput: &'a Cell<Throughput>,
+}
+
+impl Divergences<'_> {
+    fn record(&self, update: impl FnOnce(&mut Throughput)) {
+        let mut throughput = self.throughput.get();
+        update(&mut throughput);
+        self.throughput.set(throughput);
+    }
+
+    /// Consume joined storage entries up to and including `address`, or all remaining entries
+    /// if `None`. Slots of accounts before `address` belong to accounts neither side lists and
+    /// are always reported; slots of `address` itself only when `report` is set.
+    fn take_storage(&mut self, address: Option<Address>, report: bool) {
+        loop {
+            let entry_address = match self.storage.peek() {
+                None => return,
+                Some(Ok(((entry_address, _), _, _))) => *entry_address,
+                Some(Err(_)) => {
+                    if let Some(Err(e)) = self.storage.next() {
+                        self.pending.push_back(Err(e));
+                    }
+                    continue;
+                }
+            };
+            if address.is_some_and(|address| entry_address > address) {
+                return;
+            }
+            let Some(Ok(((entry_address, slot), left, right))) = self.storage.next() else {
+                return;
+            };
+            self.record(|throughput| throughput.slots += 1);
+            if left != right && (report || Some(entry_address) != address) {
+                self.pending.push_back(Ok(Divergence::Storage {
+                    address: entry_address,
+                    slot,
+                    left,
+                    right,
+                }));
+            }
+        }
+    }
+
+    /// Compare the storage roots of `address` and diff its slots only when they differ.
+    fn diff_storage_root(
+        &mut self,
+        left: &dyn StateSource,
+        right: &dyn StateSource,
+        address: Address,
+    ) -> eyre::Result<()> {
+        if left.storage_root(address)? == right.storage_root(address)? {
+            return Ok(());
+        }
+        let (left, right) = (left.storage(address)?, right.storage(address)?);
+        for entry in merge_join(left.into_iter().map(Ok), right.into_iter().map(Ok)) {
+            let (slot, left, right) = entry?;
+            self.record(|throughput| throughput.slots += 1);
+            if left != right {
+                self.pending.push_back(Ok(Divergence::Storage {
+                    address,
+                    slot,
+                    left,
+                    right,
+                }));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Divergences<'_> {
+    type Item = eyre::Result<Divergence>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(divergence) = self.pending.pop_front() {
+                return Some(divergence);
+            }
+            if let Some(account) = self.accounts.next() {
+                match account {
+                    Ok((address, left, right)) => {
+                        self.record(|throughput| throughput.accounts += 1);
+                        let divergences =
+                            StateDiffer::diff_account(address, left.as_ref(), right.as_ref());
+                        self.pending.extend(divergences.into_iter().map(Ok));
+                        let both = left.is_some() && right.is_some();
+                        match self.storage_roots {
+                            Some((left_source, right_source)) if both => {
+                                let diffed =
+                                    self.diff_storage_root(left_source, right_source, address);
+                                if let Err(e) = diffed {
+                                    self.pending.push_back(Err(e));
+                                }
+                            }
+                            _ => self.take_storage(Some(address), both),
+                        }
+                    }
+                    Err(e) => self.pending.push_back(Err(e)),
+                }
+                continue;
+            }
+            if self.storage.peek().is_some() {
+                self.take_storage(None, true);
+                continue;
+            }
+            if let Some(contract) = self.contracts.next() {
+                match contract {
+                    Ok((code_hash, left, right)) => {
+                        self.record(|throughput| throughput.contracts += 1);
+                        let divergence =
+                            StateDiffer::diff_contract(code_hash, left.as_ref(), right.as_ref());
+                        self.pending.extend(divergence.map(Ok));
+                    }
+                    Err(e) => self.pending.push_back(Err(e)),
+                }
+                continue;
+            }
+            return None;
+        }
+    }
+}
+
+/// Number of shards handed out per worker, so that dense address ranges don't leave the other
+/// workers idle.
+const SHARDS_PER_JOB: usize = 16;
+
+/// Diff the key space split into shards on `jobs` worker threads.
+///
+/// `open` is called once on each worker to open that worker's pair of sources, so database
+/// transactions never cross threads. The account shards are diffed before the bytecode shards,
+/// and divergences are passed to `emit` in that order whichever worker finishes first, so the
+/// output is the same as a sequential diff for any number of jobs. Returning `false` from
+/// `emit` stops all workers. `storage_roots` is passed to
+/// [`StateDiffer::with_storage_roots`].
+pub fn diff_parallel<'a, O>(
+    jobs: usize,
+    storage_roots: bool,
+    open: O,
+    mut emit: impl FnMut(Divergence) -> eyre::Result<bool>,
+) -> eyre::Result<Throughput>
+where
+    O: Fn() -> eyre::Result<(Box<dyn StateSource + 'a>, Box<dyn StateSource + 'a>)> + Sync,
+{
+    let started = Instant::now();
+    let shards = Shard::split(jobs * SHARDS_PER_JOB);
+    let next_shard = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel::<(usize, eyre::Result<Vec<Divergence>>)>();
+
+    let throughput = std::thread::scope(|scope| -> eyre::Result<Throughput> {
+        let workers: Vec<_> = (0..jobs.max(1))
+            .map(|_| {
+                let sender = sender.clone();
+                let (open, shards, next_shard, stop) = (&open, &shards, &next_shard, &stop);
+                scope.spawn(move || -> eyre::Result<Throughput> {
+                    let (left, right) = open()?;
+                    let differ =
+                        StateDiffer::new(&*left, &*right).with_storage_roots(storage_roots);
+                    while !stop.load(AtomicOrdering::Relaxed) {
+                        let index = next_shard.fetch_add(1, AtomicOrdering::Relaxed);
+                        // Indices past the account shards are the same shards of the code
+                        // hash space.
+                        let divergences = match shards.get(index) {
+                            Some(shard) => differ.account_divergences_in(*shard).collect(),
+                            None => match shards.get(index - shards.len()) {
+                                Some(shard) => differ.contract_divergences_in(*shard).collect(),
+                                None => break,
+                            },
+                        };
+                        if sender.send((index, divergences)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(differ.throughput())
+                })
+            })
+            .collect();
+        drop(sender);
+
+        let mut pending = BTreeMap::new();
+        let mut next = 0;
+        let mut result = Ok(());
+        'receive: for (index, divergences) in receiver.iter() {
+            pending.insert(index, divergences);
+            while let Some(divergences) = pending.remove(&next) {
+                next += 1;
+                let keep_going = divergences.and_then(|divergences| {
+                    for divergence in divergences {
+                        if !emit(divergence)? {
+                            return Ok(false);
+                        }
+                    }
+                    Ok(true)
+                });
+                if !matches!(keep_going, Ok(true)) {
+                    result = keep_going.map(|_| ());
+                    stop.store(true, AtomicOrdering::Relaxed);
+                    break 'receive;
+                }
+            }
+        }
+        drop(receiver);
+
+        let mut throughput = Throughput::default();
+        for worker in workers {
+            let worker = worker
+                .join()
+                .map_err(|_| eyre::eyre!("diff worker panicked"))??;
+            throughput = throughput + worker;
+        }
+        result.map(|_| throughput)
+    })?;
+
+    Ok(Throughput {
+        elapsed: started.elapsed(),
+        ..throughput
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: Address = Address::repeat_byte(0x10);
+    const B: Address = Address::repeat_byte(0x30);
+
+    /// A source whose storage may hold slots of addresses without an account, and whose
+    /// storage stream can fail part way through.
+    #[derive(Default)]
+    struct Fixture {
+        accounts: BTreeMap<Address, DbAccountInfo>,
+        storage: BTreeMap<(Address, B256), U256>,
+        contracts: BTreeMap<B256, Bytes>,
+        /// Yield an error after this many storage entries.
+        storage_error_after: Option<usize>,
+    }
+
+    impl Fixture {
+        fn account(mut self, address: Address, balance: u64) -> Self {
+            let info = DbAccountInfo {
+                balance: U256::from(balance),
+                ..Default::default()
+            };
+            self.accounts.insert(address, info);
+            self
+        }
+
+        fn slot(mut self, address: Address, slot: u8, value: u64) -> Self {
+            self.storage
+                .insert((address, B256::with_last_byte(slot)), U256::from(value));
+            self
+        }
+    }
+
+    impl StateSource for Fixture {
+        fn accounts(
+            &self,
+            shard: Shard,
+        ) -> Box<dyn Iterator<Item = eyre::Result<(Address, DbAccountInfo)>> + '_> {
+            Box::new(
+                self.accounts
+                    .iter()
+                    .filter(move |(address, _)| shard.contains(address.as_slice()))
+                    .map(|(address, info)| Ok((*address, info.clone()))),
+            )
+        }
+
+        fn account(&self, address: Address) -> eyre::Result<Option<DbAccountInfo>> {
+            Ok(self.accounts.get(&address).cloned())
+        }
+
+        fn storage(&self, address: Address) -> eyre::Result<BTreeMap<B256, U256>> {
+            Ok(self
+                .storage
+                .iter()
+                .filter(|((slot_address, _), _)| *slot_address == address)
+                .map(|((_, slot), value)| (*slot, *value))
+                .collect())
+        }
+
+        fn storage_entries(
+            &self,
+            shard: Shard,
+        ) -> Box<dyn Iterator<Item = eyre::Result<((Address, B256), U256)>> + '_> {
+            let entries: Vec<_> = self
+                .storage
+                .iter()
+                .filter(|((address, _), _)| shard.contains(address.as_slice()))
+                .map(|(key, value)| Ok((*key, *value)))
+                .collect();
+            let split = self.storage_error_after.unwrap_or(entries.len());
+            let error = self
+                .storage_error_after
+                .map(|_| Err(eyre::eyre!("storage read failed")));
+            let mut entries = entries.into_iter();
+            let head: Vec<_> = entries.by_ref().take(split).collect();
+            Box::new(head.into_iter().chain(error).chain(entries))
+        }
+
+        fn bytecode(&self, code_hash: B256) -> eyre::Result<Option<Bytes>> {
+            Ok(self.contracts.get(&code_hash).cloned())
+        }
+
+        fn contracts(
+            &self,
+            shard: Shard,
+        ) -> Box<dyn Iterator<Item = eyre::Result<(B256, Bytes)>> + '_> {
+            Box::new(
+                self.contracts
+                    .iter()
+                    .filter(move |(code_hash, _)| shard.contains(code_hash.as_slice()))
+                    .map(|(code_hash, code)| Ok((*code_hash, code.clone()))),
+            )
+        }
+    }
+
+    fn storage(address: Address, slot: u8, left: Option<u64>, right: Option<u64>) -> Divergence {
+        Divergence::Storage {
+            address,
+            slot: B256::with_last_byte(slot),
+            left: left.map(U256::from),
+            right: right.map(U256::from),
+        }
+    }
+
+    fn diff(left: &Fixture, right: &Fixture) -> Vec<Divergence> {
+        StateDiffer::new(left, right)
+            .divergences()
+            .collect::<eyre::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn merge_join_yields_every_key_in_order() {
+        let left = [(1, 'a'), (3, 'b'), (5, 'c')].map(Ok);
+        let right = [(2, 'x'), (3, 'y'), (6, 'z')].map(Ok);
+        let joined: Vec<_> = merge_join(left.into_iter(), right.into_iter())
+            .collect::<eyre::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            joined,
+            [
+                (1, Some('a'), None),
+                (2, None, Some('x')),
+                (3, Some('b'), Some('y')),
+                (5, Some('c'), None),
+                (6, None, Some('z')),
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_join_passes_errors_through_and_continues() {
+        let left = vec![Ok((1, ())), Err(eyre::eyre!("boom")), Ok((3, ()))];
+        let right = vec![Ok((2, ()))];
+        let joined: Vec<_> = merge_join(left.into_iter(), right.into_iter()).collect();
+        let keys: Vec<_> = joined
+            .iter()
+            .map(|entry| entry.as_ref().ok().map(|(key, _, _)| *key))
+            .collect();
+        assert_eq!(keys, [Some(1), None, Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn reports_orphan_slots_before_between_and_after_accounts() {
+        let left = Fixture::default()
+            .account(A, 1)
+            .account(B, 1)
+            .slot(Address::repeat_byte(0x05), 1, 1)
+            .slot(Address::repeat_byte(0x20), 1, 2)
+            .slot(Address::repeat_byte(0x40), 1, 3);
+        let right = Fixture::default()
+            .account(A, 1)
+            .account(B, 1)
+            .slot(Address::repeat_byte(0x20), 1, 2)
+            .slot(Address::repeat_byte(0x40), 2, 4);
+        assert_eq!(
+            diff(&left, &right),
+            [
+                storage(Address::repeat_byte(0x05), 1, Some(1), None),
+                storage(Address::repeat_byte(0x40), 1, Some(3), None),
+                storage(Address::repeat_byte(0x40), 2, None, Some(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn one_sided_accounts_report_the_account_not_its_slots() {
+        let left = Fixture::default()
+            .account(A, 1)
+            .slot(A, 1, 1)
+            .account(B, 1)
+            .slot(B, 1, 1);
+        let right = Fixture::default().account(B, 1).slot(B, 1, 2);
+        assert_eq!(
+            diff(&left, &right),
+            [
+                Divergence::ExtraAccount {
+                    address: A,
+                    balance: U256::from(1),
+                    nonce: 0,
+                    code_hash: KECCAK_EMPTY,
+                },
+                storage(B, 1, Some(1), Some(2)),
+            ]
+        );
+        assert_eq!(
+            diff(&right, &left),
+            [
+                Divergence::MissingAccount {
+                    address: A,
+                    balance: U256::from(1),
+                    nonce: 0,
+                    code_hash: KECCAK_EMPTY,
+                },
+                storage(B, 1, Some(2), Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn storage_errors_are_reported_in_place() {
+        let mut left = Fixture::default()
+            .account(A, 1)
+            .slot(A, 1, 1)
+            .account(B, 1)
+            .slot(B, 1, 1);
+        left.storage_error_after = Some(1);
+        let right = Fixture::default().account(A, 1).account(B, 1);
+        let divergences: Vec<_> = StateDiffer::new(&left, &right).divergences().collect();
+        assert_eq!(divergences.len(), 3);
+        assert_eq!(
+            divergences[0].as_ref().unwrap(),
+            &storage(A, 1, Some(1), None)
+        );
+        assert!(divergences[1].is_err());
+        assert_eq!(
+            divergences[2].as_ref().unwrap(),
+            &storage(B, 1, Some(1), None)
+        );
+    }
+
+    #[test]
+    fn one_sided_bytecodes_are_reported_in_both_directions() {
+        let code = Bytes::from_static(&[0x00]);
+        let code_hash = alloy_primitives::keccak256(&code);
+        let mut left = Fixture::default();
+        left.contracts.insert(code_hash, code);
+        let right = Fixture::default();
+        assert_eq!(diff(&left, &right), [Divergence::ExtraCode { code_hash }]);
+        assert_eq!(diff(&right, &left), [Divergence::MissingCode { code_hash }]);
+    }
+
+    #[test]
+    fn parallel_diff_matches_sequential_for_any_jobs() {
+        let mut left = Fixture::default();
+        let mut right = Fixture::default();
+        for byte in (0..=0xf0).step_by(0x10) {
+            let address = Address::repeat_byte(byte);
+            left = left.account(address, 1).slot(address, 1, 1);
+            right = right.account(address, 2).slot(address, 1, 2);
+            let code = Bytes::from(vec![byte]);
+            left.contracts
+                .insert(alloy_primitives::keccak256(&code), code);
+        }
+        let sequential = diff(&left, &right);
+        for jobs in [1, 2, 3] {
+            let mut parallel = Vec::new();
+            diff_parallel(
+                jobs,
+                false,
+                || {
+                    let left = Box::new(&left) as Box<dyn StateSource + '_>;
+                    let right = Box::new(&right) as Box<dyn StateSource + '_>;
+                    Ok((left, right))
+                },
+                |divergence| {
+                    parallel.push(divergence);
+                    Ok(true)
+                },
+            )
+            .unwrap();
+            assert_eq!(parallel, sequential, "jobs = {jobs}");
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use crate::source::{Shard, StateSource};
+use crate::types::{AbciState, DbAccountInfo};
+use alloy_consensus::constants::KECCAK_EMPTY;
+use alloy_consensus::Header;
+use alloy_primitives::{Address, Bytes, B256, U256};
+use reth_primitives::SealedHeader;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// Everything in an abci snapshot apart from its accounts.
+#[derive(Debug, Serialize)]
+pub struct SnapshotInfo<'a> {
+    pub height: u64,
+    pub header: &'a SealedHeader<Header>,
+    pub block_hashes: Vec<(u64, B256)>,
+}
+
+impl<'a> SnapshotInfo<'a> {
+    pub fn new(state: &'a AbciState) -> Self {
+        let evm = &state.exchange.hyper_evm;
+        Self {
+            height: state.exchange.locus.context.height,
+            header: evm.latest_block2.header(),
+            block_hashes: evm.state2.block_hashes().collect(),
+        }
+    }
+
+    /// Write the human readable form: height, the latest block's header and the block hashes.
+    pub fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let header = self.header.header();
+        writeln!(out, "\x1b[1mabci height {}\x1b[0m", self.height)?;
+        writeln!(out, "block {} {:#x}", header.number, self.header.hash())?;
+        writeln!(out, "  parent_hash  {:#x}", header.parent_hash)?;
+        writeln!(out, "  state_root   {:#x}", header.state_root)?;
+        writeln!(out, "  receipts     {:#x}", header.receipts_root)?;
+        writeln!(out, "  timestamp    {}", header.timestamp)?;
+        writeln!(
+            out,
+            "  gas_used     {} / {}",
+            header.gas_used, header.gas_limit
+        )?;
+        writeln!(
+            out,
+            "\x1b[1m{} block hashes\x1b[0m",
+            self.block_hashes.len()
+        )?;
+        for (number, hash) in &self.block_hashes {
+            writeln!(out, "  {:>12} {:#x}", number, hash)?;
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot with the accounts selected for dumping, as written in JSON.
+#[derive(Debug, Serialize)]
+pub struct SnapshotDump<'a> {
+    #[serde(flatten)]
+    pub info: SnapshotInfo<'a>,
+    pub accounts: Vec<DumpAccount>,
+}
+
+/// An account with its non-zero storage and code.
+#[derive(Debug, Clone, Serialize)]
+pub struct DumpAccount {
+    pub address: Address,
+    pub balance: U256,
+    pub nonce: u64,
+    pub code_hash: B256,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<Bytes>,
+    pub storage: BTreeMap<B256, U256>,
+}
+
+impl DumpAccount {
+    /// Look up `address` in `source`, or `None` if it has no account there.
+    pub fn read(source: &dyn StateSource, address: Address) -> eyre::Result<Option<Self>> {
+        match source.account(address)? {
+            Some(info) => Ok(Some(Self::new(source, address, info)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The account `address` with `info`, completed with its storage and code from `source`.
+    pub fn new(
+        source: &dyn StateSource,
+        address: Address,
+        info: DbAccountInfo,
+    ) -> eyre::Result<Self> {
+        let code = if info.code_hash == KECCAK_EMPTY {
+            None
+        } else {
+            source.bytecode(info.code_hash)?
+        };
+        Ok(Self {
+            address,
+            balance: info.balance,
+            nonce: info.nonce,
+            code_hash: info.code_hash,
+            code,
+            storage: source.storage(address)?,
+        })
+    }
+
+    /// Write one table row for the account followed by its slots.
+    pub fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(
+            out,
+            "{} {:>40} {:>10} {:#x} {:>8}",
+            self.address,
+            self.balance,
+            self.nonce,
+            self.code_hash,
+            self.code.as_ref().map_or(0, |code| code.len())
+        )?;
+        for (slot, value) in &self.storage {
+            writeln!(out, "  {:#x} = {:#x}", slot, value)?;
+        }
+        Ok(())
+    }
+
+    /// Column headings matching [`DumpAccount::write_text`].
+    pub fn write_text_heading<W: Write>(out: &mut W) -> io::Result<()> {
+        writeln!(
+            out,
+            "\x1b[1m{:<42} {:>40} {:>10} {:<66} {:>8}\x1b[0m",
+            "address", "balance", "nonce", "code_hash", "code"
+        )
+    }
+}
+
+/// Accounts of `source` to dump: those in `addresses` if any are given, skipping addresses
+/// without an account, otherwise every account in address order. At most `limit` accounts are
+/// returned.
+pub fn dump_accounts<'a>(
+    source: &'a dyn StateSource,
+    addresses: &'a [Address],
+    limit: Option<usize>,
+) -> Box<dyn Iterator<Item = eyre::Result<DumpAccount>> + 'a> {
+    let accounts: Box<dyn Iterator<Item = eyre::Result<DumpAccount>> + 'a> = if addresses.is_empty()
+    {
+        Box::new(source.accounts(Shard::ALL).map(move |account| {
+            let (address, info) = account?;
+            DumpAccount::new(source, address, info)
+        }))
+    } else {
+        Box::new(
+            addresses
+                .iter()
+                .filter_map(move |address| DumpAccount::read(source, *address).transpose()),
+        )
+    };
+    Box::new(accounts.take(limit.unwrap_or(usize::MAX)))
+}
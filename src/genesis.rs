@@ -0,0 +1,151 @@
+use crate::source::{accounts_with_storage, StateSource};
+use crate::types::DbAccountInfo;
+use alloy_consensus::constants::KECCAK_EMPTY;
+use alloy_primitives::{Address, Bytes, B256, U256, U64};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// An account of a genesis file's `alloc`, in the format accepted by geth and reth.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GenesisAccount {
+    pub balance: U256,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<U64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<BTreeMap<B256, B256>>,
+}
+
+impl GenesisAccount {
+    /// The genesis entry of an account with `info` and non-zero `storage`. `code` must be the
+    /// bytecode behind `info.code_hash`, if it has any.
+    pub fn new(info: &DbAccountInfo, code: Option<Bytes>, storage: Vec<(B256, U256)>) -> Self {
+        Self {
+            balance: info.balance,
+            nonce: (info.nonce != 0).then_some(U64::from(info.nonce)),
+            code,
+            storage: (!storage.is_empty()).then(|| {
+                storage
+                    .into_iter()
+                    .map(|(slot, value)| (slot, B256::from(value)))
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// Write every account of `source` as a genesis `alloc` object, keyed by address.
+///
+/// Accounts are written as they are read, so only one account's slots and code are held at a
+/// time. Empty accounts without storage and slots of addresses without an account are
+/// skipped, and an account whose bytecode is missing from `source` is an error. Returns the
+/// number of accounts written.
+pub fn write_alloc<W: Write>(source: &dyn StateSource, mut out: W) -> eyre::Result<usize> {
+    let mut written = 0;
+    out.write_all(b"{")?;
+    for account in accounts_with_storage(source) {
+        let (address, info, slots) = account?;
+        if info.is_empty() && slots.is_empty() {
+            continue;
+        }
+
+        let code = if info.code_hash == KECCAK_EMPTY {
+            None
+        } else {
+            let code = source.bytecode(info.code_hash)?.ok_or_else(|| {
+                eyre::eyre!("bytecode {:#x} of {address} is missing", info.code_hash)
+            })?;
+            Some(code)
+        };
+        if written > 0 {
+            out.write_all(b",")?;
+        }
+        write_entry(&mut out, address, &GenesisAccount::new(&info, code, slots))?;
+        written += 1;
+    }
+    out.write_all(b"\n}\n")?;
+    out.flush()?;
+    Ok(written)
+}
+
+fn write_entry<W: Write>(
+    out: &mut W,
+    address: Address,
+    account: &GenesisAccount,
+) -> eyre::Result<()> {
+    out.write_all(b"\n  ")?;
+    serde_json::to_writer(&mut *out, &address)?;
+    out.write_all(b": ")?;
+    serde_json::to_writer(&mut *out, account)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abci::InMemorySource;
+
+    #[test]
+    fn written_alloc_parses_back() {
+        let code = Bytes::from_static(&[0x60, 0x01, 0x60, 0x00, 0x55]);
+        let contract = DbAccountInfo {
+            balance: U256::from(5),
+            nonce: 1,
+            code_hash: alloy_primitives::keccak256(&code),
+        };
+        let eoa = DbAccountInfo {
+            balance: U256::from(7),
+            ..Default::default()
+        };
+        let source = InMemorySource::default()
+            .with_account(
+                Address::repeat_byte(0x01),
+                contract,
+                &[(B256::with_last_byte(1), U256::from(2))],
+            )
+            .with_account(Address::repeat_byte(0x02), eoa, &[])
+            .with_account(Address::repeat_byte(0x03), DbAccountInfo::default(), &[])
+            .with_contract(code.clone());
+
+        let mut out = Vec::new();
+        assert_eq!(write_alloc(&source, &mut out).unwrap(), 2);
+        let alloc: BTreeMap<Address, GenesisAccount> = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            alloc,
+            BTreeMap::from([
+                (
+                    Address::repeat_byte(0x01),
+                    GenesisAccount {
+                        balance: U256::from(5),
+                        nonce: Some(U64::from(1)),
+                        code: Some(code),
+                        storage: Some(BTreeMap::from([(
+                            B256::with_last_byte(1),
+                            B256::with_last_byte(2)
+                        )])),
+                    }
+                ),
+                (
+                    Address::repeat_byte(0x02),
+                    GenesisAccount {
+                        balance: U256::from(7),
+                        ..Default::default()
+                    }
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn missing_bytecode_is_an_error() {
+        let info = DbAccountInfo {
+            code_hash: B256::repeat_byte(0xcc),
+            ..Default::default()
+        };
+        let source = InMemorySource::default().with_account(Address::ZERO, info, &[]);
+        let error = write_alloc(&source, Vec::new()).unwrap_err().to_string();
+        assert!(error.contains("is missing"), "{error}");
+    }
+}
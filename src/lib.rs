@@ -1 +1,15 @@
+pub mod abci;
+pub mod chain;
+pub mod checkpoint;
+pub mod diff;
+pub mod dump;
+pub mod genesis;
+pub mod patch;
+pub mod receipts;
+pub mod repair;
+pub mod replay;
+pub mod report;
+pub mod reth;
+pub mod source;
+pub mod trie;
 pub mod types;
@@ -1,33 +1,55 @@
 // Using rmp(rust-messagepack), read ~/abci_state.rmp.
 
-use alloy_consensus::constants::KECCAK_EMPTY;
 use alloy_eips::BlockNumberOrTag;
 use alloy_primitives::{Address, B256, U256};
-use clap::Parser;
-use evm_diff::types::{AbciState, DbAccountInfo, EvmBlock, EvmDb};
+use clap::{CommandFactory, Parser};
+use evm_diff::abci::{open_evm_db, read_abci_state};
+use evm_diff::chain::{diff_block_hashes, diff_headers};
+use evm_diff::checkpoint::{default_evm_db_root, find_checkpoint, write_checkpoint, Checkpoint};
+use evm_diff::diff::{diff_parallel, StateDiffer};
+use evm_diff::dump::{dump_accounts, DumpAccount, SnapshotDump, SnapshotInfo};
+use evm_diff::genesis::write_alloc;
+use evm_diff::patch::StatePatch;
+use evm_diff::receipts::{self, diff_receipts_root, diff_system_txs, read_blocks, system_tx_count};
+use evm_diff::repair::{apply_repair, plan_repair, reset_trie_stages};
+use evm_diff::replay::diff_post_state;
+use evm_diff::report::{Divergence, Labels, OutputFormat, Reporter, Summary};
+use evm_diff::reth::{
+    account_history, storage_history, ChangesetOverlay, History, HistoryDivergence, RethSource,
+};
+use evm_diff::source::StateSource;
+use evm_diff::trie;
+use evm_diff::types::{
+    BlockAndReceipts, DbAccountInfo, EvmDb, ReadPrecompileCalls, ReadPrecompileInput,
+    ReadPrecompileResult,
+};
 use reth_cli_commands::common::{AccessRights, CliNodeTypes, EnvironmentArgs};
-use reth_db::cursor::{DbCursorRO, DbDupCursorRO};
-use reth_db::transaction::DbTx;
-use reth_db::{tables, DatabaseEnv};
+use reth_db::DatabaseEnv;
+use reth_evm::execute::Executor;
+use reth_evm::ConfigureEvm;
 use reth_hl::chainspec::parser::HlChainSpecParser;
 use reth_hl::chainspec::HlChainSpec;
+use reth_hl::node::evm::config::HlEvmConfig;
+use reth_hl::node::types::ReadPrecompileResult as StoredPrecompileResult;
 use reth_hl::node::HlNode;
 use reth_hl::HlPrimitives;
 use reth_node_types::NodeTypesWithDBAdapter;
-use reth_primitives::{Account, Bytecode};
 use reth_provider::providers::BlockchainProvider;
 use reth_provider::{
-    DBProvider, DatabaseProviderFactory, ProviderFactory, ProviderResult,
-    StateProvider, StateProviderFactory,
+    BlockHashReader, BlockNumReader, BlockReader, ChainSpecProvider, DBProvider,
+    DatabaseProviderFactory, HeaderProvider, ProviderFactory, ReceiptProvider,
+    StateProviderFactory, TransactionVariant, TransactionsProvider,
 };
-use rocksdb::{Options, DB};
-use std::collections::{BTreeMap, HashMap};
+use reth_revm::database::StateProviderDatabase;
+use std::ffi::OsString;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Arc;
 
 pub fn get_reth_factory<N: CliNodeTypes<ChainSpec = HlChainSpec, Primitives = HlPrimitives>>(
-    env: EnvironmentArgs<HlChainSpecParser>,
+    env: &EnvironmentArgs<HlChainSpecParser>,
 ) -> eyre::Result<ProviderFactory<NodeTypesWithDBAdapter<N, Arc<DatabaseEnv>>>> {
     let env = env.init::<N>(AccessRights::RO)?;
     Ok(env.provider_factory)
@@ -35,266 +57,1239 @@ pub fn get_reth_factory<N: CliNodeTypes<ChainSpec = HlChainSpec, Primitives = Hl
 
 #[derive(Parser)]
 enum Subcommands {
+    /// Diff an abci state and its latest block header against reth
     #[command(name = "diff")]
-    Diff(EnvironmentArgs<HlChainSpecParser>),
+    Diff {
+        /// Path to the abci state
+        file: PathBuf,
+
+        #[command(flatten)]
+        checkpoint: CheckpointArgs,
+
+        /// Compute the abci state root first and skip the slot level diff when it matches
+        /// reth's header
+        #[arg(long)]
+        state_root: bool,
+
+        #[command(flatten)]
+        diff: StateDiffArgs,
+
+        #[command(flatten)]
+        env: EnvironmentArgs<HlChainSpecParser>,
+    },
+    /// Diff two reth datadirs, or one datadir at two block heights
+    #[command(name = "diff-reth")]
+    DiffReth {
+        #[command(flatten)]
+        env: EnvironmentArgs<HlChainSpecParser>,
+
+        /// Datadir of the second node. Defaults to `--datadir`
+        #[arg(long)]
+        other_datadir: Option<PathBuf>,
+
+        /// Block to compare on the first node. Defaults to its latest block
+        #[arg(long)]
+        block: Option<u64>,
+
+        /// Block to compare on the second node. Defaults to `--block`
+        #[arg(long)]
+        other_block: Option<u64>,
+
+        #[command(flatten)]
+        diff: StateDiffArgs,
+    },
+    /// List the reth blocks that wrote an account or slot and find where it stopped matching
+    /// the abci state
+    #[command(name = "bisect")]
+    Bisect {
+        /// Path to the abci state holding the expected value
+        file: PathBuf,
+
+        /// Account to trace
+        #[arg(long)]
+        address: Address,
+
+        /// Storage slot of `--address` to trace instead of the account itself
+        #[arg(long)]
+        slot: Option<B256>,
+
+        #[command(flatten)]
+        checkpoint: CheckpointArgs,
+
+        #[command(flatten)]
+        env: EnvironmentArgs<HlChainSpecParser>,
+    },
+    /// Re-execute the abci state's latest block on top of reth's state at its parent and diff
+    /// the result against both reth and the abci state
+    #[command(name = "replay")]
+    Replay {
+        /// Path to the abci state
+        file: PathBuf,
+
+        #[command(flatten)]
+        checkpoint: CheckpointArgs,
+
+        #[command(flatten)]
+        report: ReportArgs,
+
+        #[command(flatten)]
+        env: EnvironmentArgs<HlChainSpecParser>,
+    },
+    /// Diff HyperEVM block and receipt files against reth's stored system transactions,
+    /// receipts and read precompile calls
+    #[command(name = "diff-receipts")]
+    DiffReceipts {
+        /// Files of rmp encoded blocks with receipts, optionally lz4 compressed
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        #[command(flatten)]
+        report: ReportArgs,
+
+        #[command(flatten)]
+        env: EnvironmentArgs<HlChainSpecParser>,
+    },
+    /// Overwrite reth's divergent accounts, storage and bytecode with the abci state. Only
+    /// previews the writes unless `--apply` is given
+    #[command(name = "repair")]
+    Repair {
+        /// Path to the abci state, taken as authoritative
+        #[arg(required_unless_present = "patch")]
+        file: Option<PathBuf>,
+
+        /// Apply a patch written by `--patch-out` instead of diffing an abci state. The values
+        /// it recorded for reth must still be current
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "file",
+                "evm_db_root",
+                "checkpoint_dir",
+                "jobs",
+                "storage_roots",
+                "patch_out",
+                "max_mismatches",
+            ]
+        )]
+        patch: Option<PathBuf>,
+
+        /// Write to the reth database instead of only previewing
+        #[arg(long)]
+        apply: bool,
+
+        /// Where to save the replaced values before applying. Must not exist yet.
+        /// Defaults to `repair-backup-<block>.json`
+        #[arg(long)]
+        backup: Option<PathBuf>,
+
+        #[command(flatten)]
+        checkpoint: CheckpointArgs,
+
+        #[command(flatten)]
+        diff: StateDiffArgs,
+
+        #[command(flatten)]
+        env: EnvironmentArgs<HlChainSpecParser>,
+    },
+    /// Diff two abci states, e.g. from two validators at the same height
+    #[command(name = "diff-abci")]
+    DiffAbci {
+        /// Path to the first abci state
+        file: PathBuf,
+
+        /// Path to the second abci state
+        other_file: PathBuf,
+
+        #[command(flatten)]
+        checkpoint: CheckpointArgs,
+
+        /// RocksDB checkpoint of the second abci state. Defaults to discovery under
+        /// `--evm-db-root`
+        #[arg(long)]
+        other_checkpoint_dir: Option<PathBuf>,
+
+        #[command(flatten)]
+        diff: StateDiffArgs,
+    },
+    /// Print the contents of an abci state: its height, latest block, block hashes and accounts
+    #[command(name = "dump")]
+    Dump {
+        /// Path to the abci state
+        file: PathBuf,
+
+        /// Only print these accounts. May be repeated
+        #[arg(long)]
+        address: Vec<Address>,
+
+        /// Print at most this many accounts
+        #[arg(long)]
+        limit: Option<usize>,
+
+        #[command(flatten)]
+        checkpoint: CheckpointArgs,
+    },
+    /// Write the abci state as a genesis `alloc` object, e.g. to boot a dev node from it
+    #[command(name = "export-alloc")]
+    ExportAlloc {
+        /// Path to the abci state
+        file: PathBuf,
+
+        /// File to write the alloc JSON to. Defaults to stdout
+        #[arg(long, short)]
+        out: Option<PathBuf>,
+
+        #[command(flatten)]
+        checkpoint: CheckpointArgs,
+    },
+    /// Write reth's state at a block into a new RocksDB in the abci checkpoint layout
+    #[command(name = "export-checkpoint")]
+    ExportCheckpoint {
+        /// Directory to create the RocksDB in. Must not hold a database already
+        out: PathBuf,
+
+        /// Block whose state to export. Defaults to the latest block
+        #[arg(long)]
+        block: Option<u64>,
+
+        /// Reopen the written checkpoint and diff it against reth. The diff options below only
+        /// apply to this check
+        #[arg(long)]
+        verify: bool,
+
+        #[command(flatten)]
+        diff: StateDiffArgs,
+
+        #[command(flatten)]
+        env: EnvironmentArgs<HlChainSpecParser>,
+    },
+}
+
+/// Where to find the RocksDB checkpoint backing an abci state without an in-memory evm db.
+#[derive(clap::Args)]
+struct CheckpointArgs {
+    /// Root of the evm db, containing `checkpoint/<height>/EvmState`.
+    /// Defaults to `$HOME/hl/hyperliquid_data/evm_db_hub_slow`
+    #[arg(long)]
+    evm_db_root: Option<PathBuf>,
+
+    /// RocksDB checkpoint to open, skipping discovery under `--evm-db-root`
+    #[arg(long)]
+    checkpoint_dir: Option<PathBuf>,
+}
+
+impl CheckpointArgs {
+    /// Checkpoint for the abci state at `height`: `dir` if given, otherwise the checkpoint
+    /// nearest to `height` under the evm db root.
+    fn resolve(&self, dir: Option<&Path>, height: u64) -> eyre::Result<PathBuf> {
+        if let Some(dir) = dir {
+            return Ok(dir.to_path_buf());
+        }
+        let root = match &self.evm_db_root {
+            Some(root) => root.clone(),
+            None => default_evm_db_root()?,
+        };
+        let (found, path) = find_checkpoint(&root, height)?;
+        if found > height {
+            eprintln!(
+                "Warning: no checkpoint at or below abci height {height}, using the later {found}"
+            );
+        } else if found != height {
+            eprintln!("Warning: no checkpoint at abci height {height}, using {found}");
+        }
+        eprintln!("Opening checkpoint {}", path.display());
+        Ok(path)
+    }
 }
 
 #[derive(Parser)]
 struct Args {
-    /// Path to the abci state
-    file: String,
+    #[command(flatten)]
+    opts: DiffOptions,
 
     #[command(subcommand)]
-    pub diff: Subcommands,
+    pub command: Subcommands,
 }
 
-/// Represents the complete state of a contract including account info, bytecode, and storage
-/// From https://github.com/paradigmxyz/reth/pull/17601
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ContractState {
-    /// The address of the contract
-    pub address: Address,
-    /// Basic account information (balance, nonce, code hash)
-    pub account: Account,
-    /// Contract bytecode (None if not a contract or doesn't exist)
-    pub bytecode: Option<Bytecode>,
-    /// All storage slots for the contract
-    pub storage: BTreeMap<B256, U256>,
+#[derive(clap::Args)]
+struct DiffOptions {
+    /// Output format for divergences and dumps: text, json or ndjson
+    #[arg(long, global = true, default_value = "text")]
+    format: OutputFormat,
 }
 
-/// Extract the full state of a specific contract
-pub fn extract_contract_state<P: DBProvider>(
-    provider: &P,
-    state_provider: &dyn StateProvider,
-    contract_address: Address,
-) -> ProviderResult<Option<ContractState>> {
-    let account = state_provider.basic_account(&contract_address)?;
-    let Some(account) = account else {
-        return Ok(None);
+impl DiffOptions {
+    fn reporter(
+        &self,
+        block_number: u64,
+        labels: Labels,
+        report: &ReportArgs,
+    ) -> Reporter<Box<dyn Write>> {
+        let out: Box<dyn Write> = match self.format {
+            OutputFormat::Text => Box::new(std::io::stderr()),
+            OutputFormat::Json | OutputFormat::Ndjson => Box::new(std::io::stdout().lock()),
+        };
+        Reporter::new(self.format, block_number, out)
+            .with_labels(labels)
+            .with_max_mismatches(report.max_mismatches)
+    }
+}
+
+/// Options of the commands that report divergences.
+#[derive(clap::Args, Default)]
+struct ReportArgs {
+    /// Stop after this many divergences have been reported
+    #[arg(long)]
+    max_mismatches: Option<usize>,
+}
+
+/// Options of the commands that diff two full states.
+#[derive(clap::Args)]
+struct StateDiffArgs {
+    #[command(flatten)]
+    report: ReportArgs,
+
+    /// Number of worker threads, each diffing its own address ranges
+    #[arg(long, default_value_t = 1)]
+    jobs: usize,
+
+    /// Compare per-account storage roots and only diff the slots of accounts whose roots differ
+    #[arg(long)]
+    storage_roots: bool,
+
+    /// Write the state divergences as a patch file that `repair --patch` can apply
+    #[arg(long)]
+    patch_out: Option<PathBuf>,
+}
+
+/// Exit codes: 0 when both sides agree, 1 when divergences were found, 2 on error.
+fn main() -> ExitCode {
+    match run(Args::parse_from(legacy_args(std::env::args_os().collect()))) {
+        Ok(None) => ExitCode::SUCCESS,
+        Ok(Some(summary)) => {
+            let _ = summary.write_table(&mut std::io::stderr());
+            if summary.is_clean() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(1)
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// Accept the original `evm-diff <file> diff ...` form by moving `diff` in front of the abci
+/// state path, which is where clap expects the subcommand now.
+fn legacy_args(mut args: Vec<OsString>) -> Vec<OsString> {
+    let command = Args::command();
+    let is_subcommand = |arg: &OsString| {
+        command
+            .get_subcommands()
+            .any(|subcommand| arg.to_str() == Some(subcommand.get_name()))
     };
+    if args.get(1).is_none_or(is_subcommand) {
+        return args;
+    }
+    let Some(index) = args.iter().position(|arg| arg == "diff") else {
+        return args;
+    };
+    // The old form only took `--format` and `--max-mismatches` before the path.
+    let before = &args[1..index];
+    let has_path = before.iter().enumerate().any(|(i, arg)| {
+        let is_value = i > 0
+            && matches!(
+                before[i - 1].to_str(),
+                Some("--format" | "--max-mismatches")
+            );
+        !arg.to_string_lossy().starts_with('-') && !is_value
+    });
+    if has_path {
+        eprintln!("Warning: `evm-diff <file> diff` is deprecated, use `evm-diff diff <file>`");
+        let diff = args.remove(index);
+        args.insert(1, diff);
+    }
+    args
+}
 
-    let bytecode = state_provider.account_code(&contract_address)?;
+/// Run a subcommand. Commands that don't diff anything return no summary.
+fn run(args: Args) -> eyre::Result<Option<Summary>> {
+    let opts = &args.opts;
+    let summary = match args.command {
+        Subcommands::Diff {
+            file,
+            checkpoint,
+            state_root,
+            diff: diff_args,
+            env,
+        } => diff(opts, &diff_args, &file, &checkpoint, state_root, &env)?,
+        Subcommands::DiffReth {
+            env,
+            other_datadir,
+            block,
+            other_block,
+            diff,
+        } => diff_reth(opts, &diff, env, other_datadir, block, other_block)?,
+        Subcommands::DiffAbci {
+            file,
+            other_file,
+            checkpoint,
+            other_checkpoint_dir,
+            diff,
+        } => diff_abci(
+            opts,
+            &diff,
+            &file,
+            &other_file,
+            &checkpoint,
+            other_checkpoint_dir.as_deref(),
+        )?,
+        Subcommands::Bisect {
+            file,
+            address,
+            slot,
+            checkpoint,
+            env,
+        } => bisect(opts, &file, address, slot, &checkpoint, &env)?,
+        Subcommands::Replay {
+            file,
+            checkpoint,
+            report,
+            env,
+        } => replay(opts, &report, &file, &checkpoint, &env)?,
+        Subcommands::DiffReceipts { files, report, env } => {
+            diff_receipts(opts, &report, &files, &env)?
+        }
+        Subcommands::Repair {
+            file,
+            patch,
+            apply,
+            backup,
+            checkpoint,
+            diff,
+            env,
+        } => repair(
+            opts,
+            &diff,
+            file.as_deref(),
+            patch.as_deref(),
+            apply,
+            backup,
+            &checkpoint,
+            &env,
+        )?,
+        Subcommands::Dump {
+            file,
+            address,
+            limit,
+            checkpoint,
+        } => {
+            dump(opts, &file, &address, limit, &checkpoint)?;
+            return Ok(None);
+        }
+        Subcommands::ExportAlloc {
+            file,
+            out,
+            checkpoint,
+        } => {
+            export_alloc(&file, out.as_deref(), &checkpoint)?;
+            return Ok(None);
+        }
+        Subcommands::ExportCheckpoint {
+            out,
+            block,
+            verify,
+            diff,
+            env,
+        } => return export_checkpoint(opts, &diff, &out, block, verify, &env),
+    };
+    Ok(Some(summary))
+}
 
-    let mut storage_cursor = provider
-        .tx_ref()
-        .cursor_dup_read::<tables::PlainStorageState>()?;
-    let mut storage = BTreeMap::new();
+type RethProvider = BlockchainProvider<NodeTypesWithDBAdapter<HlNode, Arc<DatabaseEnv>>>;
 
-    if let Some((_, first_entry)) = storage_cursor.seek_exact(contract_address)? {
-        storage.insert(first_entry.key, first_entry.value);
+type SourcePair<'a> = (Box<dyn StateSource + 'a>, Box<dyn StateSource + 'a>);
 
-        while let Some((_, entry)) = storage_cursor.next_dup()? {
-            storage.insert(entry.key, entry.value);
+/// Reth's state at one block, with the changesets above it collected once and shared by every
+/// source opened on it.
+struct RethAtBlock<'a> {
+    provider: &'a RethProvider,
+    block: u64,
+    overlay: Arc<ChangesetOverlay>,
+}
+
+impl<'a> RethAtBlock<'a> {
+    fn new(provider: &'a RethProvider, block: u64) -> eyre::Result<Self> {
+        let overlay = ChangesetOverlay::after(&provider.database_provider_ro()?, block)?;
+        Ok(Self {
+            provider,
+            block,
+            overlay: Arc::new(overlay),
+        })
+    }
+
+    /// Open the state with its own read transaction.
+    fn open(&self) -> eyre::Result<Box<dyn StateSource>> {
+        let source = RethSource::new(
+            self.provider.database_provider_ro()?,
+            self.provider
+                .state_by_block_number_or_tag(BlockNumberOrTag::Number(self.block))?,
+        )
+        .with_overlay(self.overlay.clone());
+        Ok(Box::new(source))
+    }
+}
+
+/// Feed every divergence between the sources returned by `open` into `reporter`. With more
+/// than one job, `open` is called once per worker thread. Writes a patch file if `--patch-out`
+/// was given.
+fn report_divergences<'a, W: Write>(
+    args: &StateDiffArgs,
+    open: impl Fn() -> eyre::Result<SourcePair<'a>> + Sync,
+    reporter: Reporter<W>,
+) -> eyre::Result<Summary> {
+    let (summary, patch) = report_and_patch(args, open, reporter, args.patch_out.is_some())?;
+    if let Some(patch) = patch {
+        write_patch(args, &patch)?;
+    }
+    Ok(summary)
+}
+
+/// Write `patch` to `--patch-out`, if it was given.
+fn write_patch(args: &StateDiffArgs, patch: &StatePatch) -> eyre::Result<()> {
+    if let Some(path) = &args.patch_out {
+        patch.write(path)?;
+        eprintln!(
+            "Wrote patch for {} accounts to {}",
+            patch.accounts.len(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// [`report_divergences`], additionally collecting the reported divergences into a
+/// [`StatePatch`] when `patch` is set.
+fn report_and_patch<'a, W: Write>(
+    args: &StateDiffArgs,
+    open: impl Fn() -> eyre::Result<SourcePair<'a>> + Sync,
+    mut reporter: Reporter<W>,
+    patch: bool,
+) -> eyre::Result<(Summary, Option<StatePatch>)> {
+    let mut divergences = Vec::new();
+    let mut report = |reporter: &mut Reporter<W>, divergence: Divergence| -> eyre::Result<()> {
+        if patch && !reporter.limit_reached() {
+            divergences.push(divergence.clone());
+        }
+        Ok(reporter.report(divergence)?)
+    };
+    let throughput = if args.jobs > 1 {
+        diff_parallel(args.jobs, args.storage_roots, &open, |divergence| {
+            report(&mut reporter, divergence)?;
+            Ok(!reporter.summary().truncated)
+        })?
+    } else {
+        let (left, right) = open()?;
+        let differ = StateDiffer::new(&*left, &*right)
+            .with_progress(true)
+            .with_storage_roots(args.storage_roots);
+        for divergence in differ.divergences() {
+            report(&mut reporter, divergence?)?;
+            if reporter.summary().truncated {
+                break;
+            }
         }
+        differ.throughput()
+    };
+    eprintln!("{throughput}");
+
+    let patch = if patch {
+        let (left, right) = open()?;
+        Some(StatePatch::from_divergences(
+            reporter.block_number(),
+            reporter.labels().clone(),
+            &divergences,
+            &*left,
+            &*right,
+        )?)
+    } else {
+        None
+    };
+    Ok((reporter.finish()?, patch))
+}
+
+fn diff(
+    opts: &DiffOptions,
+    args: &StateDiffArgs,
+    file: &Path,
+    checkpoint: &CheckpointArgs,
+    state_root: bool,
+    env: &EnvironmentArgs<HlChainSpecParser>,
+) -> eyre::Result<Summary> {
+    let abci_state = read_abci_state(file)?;
+    let evm = abci_state.exchange.hyper_evm;
+    let block_number = evm.latest_block2.header().number;
+    eprintln!("EVM block number to compare: {block_number}");
+
+    let factory = get_reth_factory::<HlNode>(env)?;
+    let provider = BlockchainProvider::new(factory)?;
+    let mut reporter = opts.reporter(block_number, Labels::default(), &args.report);
+    let Some(reth_header) = provider.sealed_header(block_number)? else {
+        eyre::bail!("reth has no canonical header for block {block_number}");
+    };
+    let mut chain_divergences = diff_headers(&reth_header, evm.latest_block2.header());
+
+    // Compare the BLOCKHASH window against reth's canonical hashes over the same range, so gaps
+    // on the abci side show up as well.
+    let abci_hashes: Vec<_> = evm.state2.block_hashes().collect();
+    let range = abci_hashes.iter().map(|(number, _)| *number);
+    if let (Some(first), Some(last)) = (range.clone().min(), range.max()) {
+        let reth_hashes = provider.canonical_hashes_range(first, last + 1)?;
+        chain_divergences.extend(diff_block_hashes((first..).zip(reth_hashes), abci_hashes)?);
+    }
+    for divergence in chain_divergences {
+        reporter.report(divergence)?;
     }
 
-    Ok(Some(ContractState {
-        address: contract_address,
-        account,
-        bytecode,
-        storage,
-    }))
+    let height = abci_state.exchange.locus.context.height;
+    let abci = open_evm_db(evm.state2.evm_db, || {
+        checkpoint.resolve(checkpoint.checkpoint_dir.as_deref(), height)
+    })?;
+
+    if state_root {
+        let root = trie::state_root(&*abci)?;
+        let header_root = evm.latest_block2.header().state_root;
+        if root != header_root {
+            eprintln!("Warning: abci state root {root:#x} differs from its latest block's {header_root:#x}");
+        }
+        if root == reth_header.state_root {
+            eprintln!("State root {root:#x} matches reth, skipping the state diff");
+            // Nothing to repair, but scripts passing `--patch-out` still expect the file.
+            write_patch(args, &StatePatch::new(block_number, reporter.labels().clone()))?;
+            return Ok(reporter.finish()?);
+        }
+        reporter.report(Divergence::StateRoot {
+            number: block_number,
+            left: reth_header.state_root,
+            right: root,
+        })?;
+    }
+
+    let reth = RethAtBlock::new(&provider, block_number)?;
+    report_divergences(
+        args,
+        || {
+            let abci = Box::new(&*abci) as Box<dyn StateSource + '_>;
+            Ok((reth.open()?, abci))
+        },
+        reporter,
+    )
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let Subcommands::Diff(env) = args.diff;
-    let abci_state_path: PathBuf = args.file.into();
-    let file = File::open(&abci_state_path)?;
-    let mut reader = std::io::BufReader::new(file);
+fn diff_reth(
+    opts: &DiffOptions,
+    args: &StateDiffArgs,
+    mut env: EnvironmentArgs<HlChainSpecParser>,
+    other_datadir: Option<PathBuf>,
+    block: Option<u64>,
+    other_block: Option<u64>,
+) -> eyre::Result<Summary> {
+    let left_factory = get_reth_factory::<HlNode>(&env)?;
+    // Comparing two heights of one node reuses its factory instead of opening the same
+    // environment twice.
+    let right_factory = match other_datadir {
+        Some(datadir) => {
+            env.datadir.datadir = datadir.into();
+            env.datadir.static_files_path = None;
+            get_reth_factory::<HlNode>(&env)?
+        }
+        None => left_factory.clone(),
+    };
+
+    let left_provider = BlockchainProvider::new(left_factory)?;
+    let right_provider = BlockchainProvider::new(right_factory)?;
+    let left_block = match block {
+        Some(block) => block,
+        None => left_provider.best_block_number()?,
+    };
+    let right_block = match other_block.or(block) {
+        Some(block) => block,
+        None => right_provider.best_block_number()?,
+    };
+    eprintln!("Comparing block {left_block} against block {right_block}");
+
+    let labels = Labels::new(format!("left@{left_block}"), format!("right@{right_block}"));
 
-    let abci_state: AbciState = rmp_serde::decode::from_read(&mut reader)?;
+    let left = RethAtBlock::new(&left_provider, left_block)?;
+    let right = RethAtBlock::new(&right_provider, right_block)?;
+    report_divergences(
+        args,
+        || Ok((left.open()?, right.open()?)),
+        opts.reporter(left_block, labels, &args.report),
+    )
+}
+
+fn replay(
+    opts: &DiffOptions,
+    report: &ReportArgs,
+    file: &Path,
+    checkpoint: &CheckpointArgs,
+    env: &EnvironmentArgs<HlChainSpecParser>,
+) -> eyre::Result<Summary> {
+    let abci_state = read_abci_state(file)?;
     let evm = abci_state.exchange.hyper_evm;
-    let header = match &evm.latest_block2 {
-        EvmBlock::Reth115(block) => block.header.clone(),
+    let block_number = evm.latest_block2.header().number;
+    let Some(parent_number) = block_number.checked_sub(1) else {
+        eyre::bail!("cannot replay the genesis block");
     };
-    let block_number = header.number;
-    eprintln!("EVM block number to compare: {block_number}");
+    let height = abci_state.exchange.locus.context.height;
+    let abci = open_evm_db(evm.state2.evm_db, || {
+        checkpoint.resolve(checkpoint.checkpoint_dir.as_deref(), height)
+    })?;
 
-    let factory = get_reth_factory::<HlNode>(env).unwrap();
-    let provider = BlockchainProvider::new(factory).unwrap();
-    let db_provider = provider.database_provider_ro().unwrap();
-    let state = provider
-        .state_by_block_number_or_tag(BlockNumberOrTag::Number(header.number))
-        .unwrap();
-    match evm.state2.evm_db {
-        EvmDb::InMemory {
-            accounts,
-            contracts,
-        } => {
-            let contracts: HashMap<B256, evm_diff::types::Bytecode> = contracts.into_iter().collect();
-            let reth_contracts: HashMap<B256, Bytecode> = contracts
-                .iter()
-                .map(|(h, c)| (*h, Bytecode::new_raw(c.original_bytes())))
-                .collect();
-            for (address, account) in tqdm::tqdm(accounts) {
-                diff_account(
-                    &db_provider,
-                    &state,
-                    block_number,
-                    address,
-                    &account.info,
-                    account.storage.into_iter(),
-                );
-            }
-            for (code_hash, code) in tqdm::tqdm(reth_contracts.iter()) {
-                diff_contract(&state, *code_hash, code);
-            }
+    let factory = get_reth_factory::<HlNode>(env)?;
+    let provider = BlockchainProvider::new(factory)?;
+    let Some(block) =
+        provider.recovered_block(block_number.into(), TransactionVariant::WithHash)?
+    else {
+        eyre::bail!("reth has no block {block_number}");
+    };
+
+    // System transactions and precompile results are part of the stored block body, so the
+    // HyperEVM executor replays them along with the user transactions.
+    let parent = StateProviderDatabase::new(provider.history_by_block_number(parent_number)?);
+    let output = HlEvmConfig::new(provider.chain_spec())
+        .batch_executor(parent)
+        .execute(&block)?;
+    eprintln!(
+        "Replayed block {block_number}: {} receipts, {} gas used",
+        output.result.receipts.len(),
+        output.result.gas_used
+    );
+
+    let reth = RethAtBlock::new(&provider, block_number)?.open()?;
+    let mut reporter = opts.reporter(block_number, Labels::new("replay", "reth"), report);
+    let mut clean = Vec::new();
+    for (side, source) in [("reth", &*reth), ("abci", &*abci as &dyn StateSource)] {
+        let divergences = diff_post_state(&output.state, source)?;
+        clean.push((side, divergences.is_empty()));
+        for divergence in divergences {
+            reporter.report_labelled(divergence, Labels::new("replay", side))?;
         }
-        EvmDb::NoEvmDb {} => {
-            let home = std::env::var("HOME").unwrap();
-            let db_path = PathBuf::from(home)
-                .join("hl/hyperliquid_data/evm_db_hub_slow")
-                .join("checkpoint")
-                .join(abci_state.exchange.locus.context.height.to_string())
-                .join("EvmState");
-            println!("Opening RocksDB at {:?}", db_path);
-
-            let prefix_extractor = rocksdb::SliceTransform::create_fixed_prefix(2);
-            let mut opts = Options::default();
-            opts.set_prefix_extractor(prefix_extractor);
-            let db = DB::open(&opts, &db_path).unwrap();
-
-            let mut contracts: HashMap<B256, Bytecode> = HashMap::new();
-            for entry in db.prefix_iterator(b"\x45\x63") {
-                let entry = entry.unwrap();
-                let (key, value) = entry;
-                let code_hash = B256::from_slice(&key[2..34]);
-                let bytecode: evm_diff::types::Bytecode =
-                    rmp_serde::from_slice(&value).unwrap();
-                contracts.insert(code_hash, Bytecode::new_raw(bytecode.original_bytes()));
-            }
+    }
+    let summary = reporter.finish()?;
 
-            let mut storage_iterator = db.prefix_iterator(b"\x45\x73").peekable();
-            let account_iter = db.prefix_iterator(b"\x45\x61").map(|entry| {
-                let entry = entry.unwrap();
-                let (key, value) = entry;
-                let address = Address::from_slice(&key[2..22]);
-                let info: DbAccountInfo = rmp_serde::from_slice(&value).unwrap();
-                (address, info)
-            });
+    for (side, clean) in clean {
+        if clean {
+            eprintln!("{side} agrees with the replayed post-state");
+        } else {
+            eprintln!("{side} disagrees with the replayed post-state");
+        }
+    }
+    Ok(summary)
+}
 
-            for (address, info) in tqdm::tqdm(account_iter.collect::<Vec<_>>()) {
-                let mut current_storage: Vec<(U256, U256)> = Vec::new();
-                loop {
-                    let Some(entry) = storage_iterator.peek() else { break };
-                    let entry = entry.as_ref().unwrap();
-                    let (key, _value) = entry;
-                    let storage_address = Address::from_slice(&key[2..22]);
-                    if storage_address != address {
-                        break;
-                    }
-                    let entry = storage_iterator.next().unwrap().unwrap();
-                    let (key, value) = entry;
-                    let storage_key = B256::from_slice(&key[22..54]);
-                    let storage_value: B256 = rmp_serde::from_slice(&value).unwrap();
-                    current_storage.push((storage_key.into(), storage_value.into()));
-                }
-
-                diff_account(
-                    &db_provider,
-                    &state,
-                    block_number,
-                    address,
-                    &info,
-                    current_storage.into_iter(),
-                );
-            }
+fn diff_receipts(
+    opts: &DiffOptions,
+    report: &ReportArgs,
+    files: &[PathBuf],
+    env: &EnvironmentArgs<HlChainSpecParser>,
+) -> eyre::Result<Summary> {
+    let mut blocks = Vec::new();
+    for file in files {
+        blocks.extend(read_blocks(file)?);
+    }
+    blocks.sort_by_key(|block| block.block.header().number);
+    let Some(first) = blocks.first().map(|block| block.block.header().number) else {
+        eyre::bail!("no blocks found in the given files");
+    };
 
-            for (code_hash, code) in tqdm::tqdm(contracts.iter()) {
-                diff_contract(&state, *code_hash, code);
-            }
+    let factory = get_reth_factory::<HlNode>(env)?;
+    let provider = BlockchainProvider::new(factory)?;
+    let mut reporter = opts.reporter(first, Labels::new("reth", "file"), report);
+    for block in tqdm::tqdm(blocks.iter()) {
+        for divergence in block_receipt_divergences(&provider, block)? {
+            reporter.report(divergence)?;
+        }
+        if reporter.summary().truncated {
+            break;
         }
     }
+    Ok(reporter.finish()?)
+}
 
-    Ok(())
+/// Header, system transaction, receipt, recomputed receipts root and read precompile call
+/// divergences of one block file entry against reth.
+fn block_receipt_divergences(
+    provider: &RethProvider,
+    block: &BlockAndReceipts,
+) -> eyre::Result<Vec<Divergence>> {
+    let header = block.block.header();
+    let number = header.number;
+    let Some(reth_header) = provider.sealed_header(number)? else {
+        eyre::bail!("reth has no canonical header for block {number}");
+    };
+    let mut divergences = diff_headers(&reth_header, header);
+    divergences.extend(diff_receipts_root(reth_header.header(), &block.receipts));
+
+    let reth_txs = provider
+        .transactions_by_block(number.into())?
+        .unwrap_or_default();
+    let reth_receipts = provider
+        .receipts_by_block(number.into())?
+        .unwrap_or_default();
+    divergences.extend(diff_system_txs(
+        number,
+        &reth_txs,
+        &reth_receipts,
+        &block.system_txs,
+    ));
+
+    let system = system_tx_count(&reth_txs).min(reth_receipts.len());
+    divergences.extend(receipts::diff_receipts(
+        number,
+        system,
+        &reth_receipts[system..],
+        &block.receipts,
+    ));
+
+    divergences.extend(receipts::diff_precompile_calls(
+        number,
+        &stored_precompile_calls(provider, number)?,
+        &block.read_precompile_calls,
+    )?);
+    Ok(divergences)
 }
 
-fn diff_account<P: DBProvider>(
-    db_provider: &P,
-    state: &dyn StateProvider,
-    block_number: u64,
-    address: Address,
-    info: &DbAccountInfo,
-    storage: impl Iterator<Item = (U256, U256)>,
-) {
-    let account_in_db = state.basic_account(&address);
-    match account_in_db {
-        Ok(Some(account_in_db)) => {
-            if account_in_db.balance != info.balance {
-                eprintln!("\x1b[1mBalance mismatch for {}\x1b[0m (block {})", address, block_number);
-                eprintln!("  \x1b[31m- reth: {}\x1b[0m", account_in_db.balance);
-                eprintln!("  \x1b[32m+ abci: {}\x1b[0m", info.balance);
+/// Read precompile calls nanoreth stored in the body of block `number`.
+fn stored_precompile_calls(
+    provider: &RethProvider,
+    number: u64,
+) -> eyre::Result<ReadPrecompileCalls> {
+    let Some(block) = provider.block_by_number(number)? else {
+        eyre::bail!("reth has no block {number}");
+    };
+    let Some(calls) = block.body.read_precompile_calls else {
+        return Ok(Vec::new());
+    };
+    let calls = calls.0.into_iter().map(|(address, calls)| {
+        let calls = calls
+            .into_iter()
+            .map(|(input, result)| {
+                let input = ReadPrecompileInput {
+                    input: input.input,
+                    gas_limit: input.gas_limit,
+                };
+                (input, precompile_result(result))
+            })
+            .collect();
+        (address, calls)
+    });
+    Ok(calls.collect())
+}
+
+/// Convert nanoreth's copy of a read precompile result into ours.
+fn precompile_result(result: StoredPrecompileResult) -> ReadPrecompileResult {
+    match result {
+        StoredPrecompileResult::Ok { gas_used, bytes } => {
+            ReadPrecompileResult::Ok { gas_used, bytes }
+        }
+        StoredPrecompileResult::OutOfGas => ReadPrecompileResult::OutOfGas,
+        StoredPrecompileResult::Error => ReadPrecompileResult::Error,
+        StoredPrecompileResult::UnexpectedError => ReadPrecompileResult::UnexpectedError,
+    }
+}
+
+fn repair(
+    opts: &DiffOptions,
+    args: &StateDiffArgs,
+    file: Option<&Path>,
+    patch: Option<&Path>,
+    apply: bool,
+    backup: Option<PathBuf>,
+    checkpoint: &CheckpointArgs,
+    env: &EnvironmentArgs<HlChainSpecParser>,
+) -> eyre::Result<Summary> {
+    let access = if apply {
+        AccessRights::RW
+    } else {
+        AccessRights::RO
+    };
+    let factory = env.init::<HlNode>(access)?.provider_factory;
+    let provider = BlockchainProvider::new(factory.clone())?;
+    // The plain state tables only hold the tip, so older states can't be written back.
+    let tip = provider.best_block_number()?;
+
+    let (summary, patch) = match (patch, file) {
+        (Some(path), None) => {
+            let patch = StatePatch::read(path)?;
+            // The patch only says which values to write over reth's if reth was its left side.
+            let reth = &Labels::default().left;
+            if &patch.labels.left != reth {
+                eyre::bail!(
+                    "patch {} was made against {}, not {reth}",
+                    path.display(),
+                    patch.labels.left
+                );
             }
-            if account_in_db.nonce != info.nonce {
-                eprintln!("\x1b[1mNonce mismatch for {}\x1b[0m", address);
-                eprintln!("  \x1b[31m- reth: {}\x1b[0m", account_in_db.nonce);
-                eprintln!("  \x1b[32m+ abci: {}\x1b[0m", info.nonce);
+            (Summary::default(), patch)
+        }
+        (None, Some(file)) => {
+            let abci_state = read_abci_state(file)?;
+            let evm = abci_state.exchange.hyper_evm;
+            let block_number = evm.latest_block2.header().number;
+            if tip != block_number {
+                eyre::bail!("reth is at block {tip} but the abci state is at block {block_number}");
             }
-            if account_in_db.get_bytecode_hash() != info.code_hash {
-                eprintln!("\x1b[1mCode hash mismatch for {}\x1b[0m (block {})", address, block_number);
-                eprintln!("  \x1b[31m- reth: {:#x}\x1b[0m", account_in_db.get_bytecode_hash());
-                eprintln!("  \x1b[32m+ abci: {:#x}\x1b[0m", info.code_hash);
+            let height = abci_state.exchange.locus.context.height;
+            let abci = open_evm_db(evm.state2.evm_db, || {
+                checkpoint.resolve(checkpoint.checkpoint_dir.as_deref(), height)
+            })?;
+            let reth = RethAtBlock::new(&provider, block_number)?;
+            let (summary, patch) = report_and_patch(
+                args,
+                || {
+                    let abci = Box::new(&*abci) as Box<dyn StateSource + '_>;
+                    Ok((reth.open()?, abci))
+                },
+                opts.reporter(block_number, Labels::default(), &args.report),
+                true,
+            )?;
+            if summary.truncated {
+                eyre::bail!(
+                    "--max-mismatches cut the diff short, refusing to repair a partial state"
+                );
             }
+            let patch = patch.expect("patch requested");
+            write_patch(args, &patch)?;
+            (summary, patch)
+        }
+        _ => eyre::bail!("pass either an abci state or --patch"),
+    };
+    if patch.block_number != tip {
+        eyre::bail!(
+            "reth is at block {tip} but the patch is for block {}",
+            patch.block_number
+        );
+    }
+
+    let writes = plan_repair(&patch, &*RethAtBlock::new(&provider, tip)?.open()?)?;
+    let mut stderr = std::io::stderr();
+    for write in &writes {
+        write.write_text(&mut stderr)?;
+    }
+    if !apply {
+        eprintln!(
+            "Dry run: {} writes planned, pass --apply to write them",
+            writes.len()
+        );
+        return Ok(summary);
+    }
+
+    let backup = backup.unwrap_or_else(|| PathBuf::from(format!("repair-backup-{tip}.json")));
+    // Never overwrite an earlier backup: repair doesn't move the tip, so a second run at the
+    // same block would otherwise replace the only copy of the original values.
+    let file = match File::create_new(&backup) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => eyre::bail!(
+            "backup {} already exists, pass another --backup",
+            backup.display()
+        ),
+        Err(e) => return Err(e.into()),
+    };
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer_pretty(&mut writer, &writes)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    eprintln!("Saved replaced values to {}", backup.display());
+
+    let provider_rw = factory.provider_rw()?;
+    apply_repair(provider_rw.tx_ref(), &writes)?;
+    reset_trie_stages(&*provider_rw)?;
+    provider_rw.commit()?;
+    eprintln!(
+        "Applied {} writes; hashed state and trie will be rebuilt on the next start",
+        writes.len()
+    );
+    Ok(summary)
+}
+
+fn diff_abci(
+    opts: &DiffOptions,
+    args: &StateDiffArgs,
+    file: &Path,
+    other_file: &Path,
+    checkpoint: &CheckpointArgs,
+    other_checkpoint_dir: Option<&Path>,
+) -> eyre::Result<Summary> {
+    let left_state = read_abci_state(file)?;
+    let right_state = read_abci_state(other_file)?;
+    let (left_height, right_height) = (
+        left_state.exchange.locus.context.height,
+        right_state.exchange.locus.context.height,
+    );
+    if left_height != right_height {
+        eprintln!("Warning: comparing abci heights {left_height} and {right_height}");
+    }
+    let (left_evm, right_evm) = (
+        left_state.exchange.hyper_evm,
+        right_state.exchange.hyper_evm,
+    );
+    let block_number = left_evm.latest_block2.header().number;
+    eprintln!("EVM block number to compare: {block_number}");
+
+    let mut reporter = opts.reporter(block_number, Labels::new("left", "right"), &args.report);
+    let mut chain_divergences = diff_headers(
+        left_evm.latest_block2.header(),
+        right_evm.latest_block2.header(),
+    );
+    chain_divergences.extend(diff_block_hashes(
+        left_evm.state2.block_hashes(),
+        right_evm.state2.block_hashes(),
+    )?);
+    for divergence in chain_divergences {
+        reporter.report(divergence)?;
+    }
 
-            let contract_state = extract_contract_state(db_provider, state, address)
-                .unwrap()
-                .unwrap();
-            let expected_storage: BTreeMap<B256, U256> = storage
-                .filter(|(_, v)| v != &U256::ZERO)
-                .map(|(k, v)| (k.into(), v.into()))
-                .collect();
-            if contract_state.storage != expected_storage {
-                eprintln!("\x1b[1mStorage mismatch for {}\x1b[0m", address);
-                for (key, reth_val) in &contract_state.storage {
-                    match expected_storage.get(key) {
-                        Some(abci_val) if abci_val != reth_val => {
-                            eprintln!("  \x1b[33m~ {:#x}\x1b[0m", key);
-                            eprintln!("    \x1b[31m- reth: {:#x}\x1b[0m", reth_val);
-                            eprintln!("    \x1b[32m+ abci: {:#x}\x1b[0m", abci_val);
-                        }
-                        None => {
-                            eprintln!("  \x1b[31m- {:#x} = {:#x}\x1b[0m (only in reth)", key, reth_val);
-                        }
-                        _ => {}
-                    }
-                }
-                for (key, abci_val) in &expected_storage {
-                    if !contract_state.storage.contains_key(key) {
-                        eprintln!("  \x1b[32m+ {:#x} = {:#x}\x1b[0m (only in abci)", key, abci_val);
-                    }
-                }
+    let left = open_evm_db(left_evm.state2.evm_db, || {
+        checkpoint.resolve(checkpoint.checkpoint_dir.as_deref(), left_height)
+    })?;
+    let right = open_evm_db(right_evm.state2.evm_db, || {
+        checkpoint.resolve(other_checkpoint_dir, right_height)
+    })?;
+    report_divergences(
+        args,
+        || {
+            let left = Box::new(&*left) as Box<dyn StateSource + '_>;
+            let right = Box::new(&*right) as Box<dyn StateSource + '_>;
+            Ok((left, right))
+        },
+        reporter,
+    )
+}
+
+fn bisect(
+    opts: &DiffOptions,
+    file: &Path,
+    address: Address,
+    slot: Option<B256>,
+    checkpoint: &CheckpointArgs,
+    env: &EnvironmentArgs<HlChainSpecParser>,
+) -> eyre::Result<Summary> {
+    let abci_state = read_abci_state(file)?;
+    let evm = abci_state.exchange.hyper_evm;
+    let block_number = evm.latest_block2.header().number;
+    let height = abci_state.exchange.locus.context.height;
+    let abci = open_evm_db(evm.state2.evm_db, || {
+        checkpoint.resolve(checkpoint.checkpoint_dir.as_deref(), height)
+    })?;
+    let provider = get_reth_factory::<HlNode>(env)?.provider()?;
+    let mut reporter = opts.reporter(block_number, Labels::default(), &ReportArgs::default());
+
+    match slot {
+        None => {
+            let expected = abci.account(address)?.filter(|info| !info.is_empty());
+            let history = account_history(&provider, address)?.map(|account| {
+                account
+                    .map(DbAccountInfo::from)
+                    .filter(|info| !info.is_empty())
+            });
+            print_history(&history, block_number, &expected, |info| match info {
+                Some(info) => format!(
+                    "balance {}, nonce {}, code hash {:#x}",
+                    info.balance, info.nonce, info.code_hash
+                ),
+                None => "absent".to_string(),
+            });
+            let left = history.value_at(block_number);
+            for divergence in StateDiffer::diff_account(address, left.as_ref(), expected.as_ref()) {
+                reporter.report(divergence)?;
             }
         }
-        Ok(None) => {
-            if info.balance != U256::ZERO || info.nonce != 0 || info.code_hash != KECCAK_EMPTY {
-                eprintln!("\x1b[1mAccount {} not found in reth but exists in abci\x1b[0m", address);
-                eprintln!("  balance: {}, nonce: {}, code_hash: {:#x}", info.balance, info.nonce, info.code_hash);
+        Some(slot) => {
+            let expected = abci.storage(address)?.get(&slot).copied();
+            let history = storage_history(&provider, address, slot)?
+                .map(|value| (value != U256::ZERO).then_some(value));
+            print_history(&history, block_number, &expected, |value| match value {
+                Some(value) => format!("{value:#x}"),
+                None => "unset".to_string(),
+            });
+            let left = *history.value_at(block_number);
+            if left != expected {
+                reporter.report(Divergence::Storage {
+                    address,
+                    slot,
+                    left,
+                    right: expected,
+                })?;
             }
         }
-        Err(e) => {
-            println!("Error getting account: {:x}: {}", address, e);
-        }
     }
+    Ok(reporter.finish()?)
 }
 
-fn diff_contract(
-    state: &dyn StateProvider,
-    code_hash: B256,
-    code: &Bytecode,
+/// Print every write in `history` to stderr and point out where reth stopped holding
+/// `expected`, the abci value at `block`.
+fn print_history<V: PartialEq>(
+    history: &History<V>,
+    block: u64,
+    expected: &V,
+    show: impl Fn(&V) -> String,
 ) {
-    if code_hash == KECCAK_EMPTY {
-        return;
+    eprintln!("abci value at block {block}: {}", show(expected));
+    if history.writes.is_empty() {
+        eprintln!(
+            "No writes in reth's history, current value: {}",
+            show(&history.current)
+        );
+    }
+    for write in &history.writes {
+        let marker = if write.block > block {
+            " (after abci block)"
+        } else {
+            ""
+        };
+        eprintln!(
+            "  block {:>10}: {} -> {}{}",
+            write.block,
+            show(&write.before),
+            show(&write.after),
+            marker
+        );
     }
-    let code_in_db = state.bytecode_by_hash(&code_hash).unwrap();
-    match code_in_db {
-        Some(code_in_db) => {
-            if code_in_db.original_bytes() != code.original_bytes() {
-                eprintln!("\x1b[1mBytecode mismatch for hash {:#x}\x1b[0m", code_hash);
+
+    match history.divergence(block, expected) {
+        HistoryDivergence::Matches => eprintln!("reth matches abci at block {block}"),
+        HistoryDivergence::DivergedAt {
+            block: diverged,
+            last_match,
+        } => {
+            if let Some(last_match) = last_match {
+                eprintln!("Last write matching abci: block {last_match}");
             }
+            eprintln!("Diverged at block {diverged}");
         }
-        None => {
-            eprintln!("\x1b[31mCode not found in reth: {:#x}\x1b[0m", code_hash);
+        HistoryDivergence::NeverMatched => {
+            eprintln!("reth never held the abci value up to block {block}")
+        }
+    }
+}
+
+fn dump(
+    opts: &DiffOptions,
+    file: &Path,
+    addresses: &[Address],
+    limit: Option<usize>,
+    checkpoint: &CheckpointArgs,
+) -> eyre::Result<()> {
+    let mut abci_state = read_abci_state(file)?;
+    let height = abci_state.exchange.locus.context.height;
+    // The accounts are read through the opened evm db, the rest of the snapshot stays in place.
+    let evm_db = std::mem::replace(
+        &mut abci_state.exchange.hyper_evm.state2.evm_db,
+        EvmDb::NoEvmDb {},
+    );
+    let abci = open_evm_db(evm_db, || {
+        checkpoint.resolve(checkpoint.checkpoint_dir.as_deref(), height)
+    })?;
+
+    let info = SnapshotInfo::new(&abci_state);
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    match opts.format {
+        OutputFormat::Text => {
+            info.write_text(&mut out)?;
+            DumpAccount::write_text_heading(&mut out)?;
+        }
+        OutputFormat::Json => {}
+        OutputFormat::Ndjson => {
+            serde_json::to_writer(&mut out, &info)?;
+            writeln!(out)?;
+        }
+    }
+
+    let accounts = dump_accounts(&*abci, addresses, limit);
+    if opts.format == OutputFormat::Json {
+        let accounts = accounts.collect::<eyre::Result<_>>()?;
+        serde_json::to_writer_pretty(&mut out, &SnapshotDump { info, accounts })?;
+        writeln!(out)?;
+    } else {
+        for account in accounts {
+            let account = account?;
+            if opts.format == OutputFormat::Text {
+                account.write_text(&mut out)?;
+            } else {
+                serde_json::to_writer(&mut out, &account)?;
+                writeln!(out)?;
+            }
         }
     }
+    out.flush()?;
+    Ok(())
+}
+
+fn export_alloc(file: &Path, out: Option<&Path>, checkpoint: &CheckpointArgs) -> eyre::Result<()> {
+    let abci_state = read_abci_state(file)?;
+    let evm = abci_state.exchange.hyper_evm;
+    let block_number = evm.latest_block2.header().number;
+    let height = abci_state.exchange.locus.context.height;
+    let abci = open_evm_db(evm.state2.evm_db, || {
+        checkpoint.resolve(checkpoint.checkpoint_dir.as_deref(), height)
+    })?;
+
+    let writer: Box<dyn Write> = match out {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+    let written = write_alloc(&*abci, writer)?;
+    eprintln!("Exported {written} accounts of EVM block {block_number}");
+    Ok(())
+}
+
+fn export_checkpoint(
+    opts: &DiffOptions,
+    args: &StateDiffArgs,
+    out: &Path,
+    block: Option<u64>,
+    verify: bool,
+    env: &EnvironmentArgs<HlChainSpecParser>,
+) -> eyre::Result<Option<Summary>> {
+    let provider = BlockchainProvider::new(get_reth_factory::<HlNode>(env)?)?;
+    let block_number = match block {
+        Some(block) => block,
+        None => provider.best_block_number()?,
+    };
+    let reth = RethAtBlock::new(&provider, block_number)?;
+    let counts = write_checkpoint(&*reth.open()?, out, |counts| {
+        eprint!(
+            "\rWritten {} accounts, {} slots and {} bytecodes",
+            counts.accounts, counts.slots, counts.contracts
+        );
+    })?;
+    eprintln!();
+    eprintln!(
+        "Exported {} accounts, {} slots and {} bytecodes of block {block_number} to {}",
+        counts.accounts,
+        counts.slots,
+        counts.contracts,
+        out.display()
+    );
+    if !verify {
+        return Ok(None);
+    }
+
+    let checkpoint = Checkpoint::open(out)?;
+    let summary = report_divergences(
+        args,
+        || {
+            let checkpoint = Box::new(&checkpoint) as Box<dyn StateSource + '_>;
+            Ok((reth.open()?, checkpoint))
+        },
+        opts.reporter(
+            block_number,
+            Labels::new("reth", "checkpoint"),
+            &args.report,
+        ),
+    )?;
+    Ok(Some(summary))
 }
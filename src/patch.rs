@@ -0,0 +1,283 @@
+use crate::report::{Divergence, Labels};
+use crate::source::StateSource;
+use alloy_consensus::constants::KECCAK_EMPTY;
+use alloy_primitives::{Address, Bytes, B256, U256};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+/// Format version written to patch files. Files with another version are rejected.
+pub const PATCH_VERSION: u32 = 1;
+
+/// A value that differs between the two sides. Applying the patch sets `right`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change<T> {
+    pub left: T,
+    pub right: T,
+}
+
+impl<T> Change<T> {
+    /// A value held only on the left (`on_left`) or the right side, `empty` on the other.
+    fn one_sided(on_left: bool, value: T, empty: T) -> Self {
+        if on_left {
+            Self {
+                left: value,
+                right: empty,
+            }
+        } else {
+            Self {
+                left: empty,
+                right: value,
+            }
+        }
+    }
+}
+
+/// Changes to one account. Fields that agree are omitted.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountPatch {
+    /// Set when the account exists on one side only; applying creates or removes it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exists: Option<Change<bool>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub balance: Option<Change<U256>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<Change<u64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code_hash: Option<Change<B256>>,
+    /// Slot changes; `None` means the slot is unset on that side.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub storage: BTreeMap<B256, Change<Option<U256>>>,
+}
+
+/// A reviewable set of changes that makes the left side of a diff match its right side at
+/// `block_number`, with the values both sides held when the patch was made.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatePatch {
+    pub version: u32,
+    pub block_number: u64,
+    #[serde(flatten)]
+    pub labels: Labels,
+    pub accounts: BTreeMap<Address, AccountPatch>,
+    /// Bytecode changes; `None` means the code is missing on that side.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub bytecodes: BTreeMap<B256, Change<Option<Bytes>>>,
+}
+
+impl StatePatch {
+    pub fn new(block_number: u64, labels: Labels) -> Self {
+        Self {
+            version: PATCH_VERSION,
+            block_number,
+            labels,
+            accounts: BTreeMap::new(),
+            bytecodes: BTreeMap::new(),
+        }
+    }
+
+    /// Build a patch from the divergences between `left` and `right`.
+    ///
+    /// The sources fill in what divergences don't carry: the storage of accounts that exist on
+    /// one side only, and bytecode. Header and chain divergences are not state and are skipped.
+    pub fn from_divergences(
+        block_number: u64,
+        labels: Labels,
+        divergences: &[Divergence],
+        left: &dyn StateSource,
+        right: &dyn StateSource,
+    ) -> eyre::Result<Self> {
+        let mut patch = Self::new(block_number, labels);
+        let mut one_sided = BTreeSet::new();
+        for divergence in divergences {
+            match divergence.clone() {
+                Divergence::Balance {
+                    address,
+                    left,
+                    right,
+                } => {
+                    patch.account(address).balance = Some(Change { left, right });
+                }
+                Divergence::Nonce {
+                    address,
+                    left,
+                    right,
+                } => {
+                    patch.account(address).nonce = Some(Change { left, right });
+                }
+                Divergence::CodeHash {
+                    address,
+                    left,
+                    right,
+                } => {
+                    patch.account(address).code_hash = Some(Change { left, right });
+                }
+                Divergence::Storage {
+                    address,
+                    slot,
+                    left,
+                    right,
+                } => {
+                    patch
+                        .account(address)
+                        .storage
+                        .insert(slot, Change { left, right });
+                }
+                Divergence::MissingAccount {
+                    address,
+                    balance,
+                    nonce,
+                    code_hash,
+                } => {
+                    patch
+                        .account(address)
+                        .set_one_sided(false, balance, nonce, code_hash);
+                    one_sided.insert(address);
+                }
+                Divergence::ExtraAccount {
+                    address,
+                    balance,
+                    nonce,
+                    code_hash,
+                } => {
+                    patch
+                        .account(address)
+                        .set_one_sided(true, balance, nonce, code_hash);
+                    one_sided.insert(address);
+                }
+                Divergence::MissingCode { code_hash }
+                | Divergence::ExtraCode { code_hash }
+                | Divergence::Bytecode { code_hash } => {
+                    let change = Change {
+                        left: left.bytecode(code_hash)?,
+                        right: right.bytecode(code_hash)?,
+                    };
+                    patch.bytecodes.insert(code_hash, change);
+                }
+                _ => {}
+            }
+        }
+
+        for address in one_sided {
+            let (left, right) = (left.storage(address)?, right.storage(address)?);
+            let storage = &mut patch.account(address).storage;
+            for slot in left.keys().chain(right.keys()) {
+                let change = Change {
+                    left: left.get(slot).copied(),
+                    right: right.get(slot).copied(),
+                };
+                storage.insert(*slot, change);
+            }
+        }
+        Ok(patch)
+    }
+
+    fn account(&mut self, address: Address) -> &mut AccountPatch {
+        self.accounts.entry(address).or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty() && self.bytecodes.is_empty()
+    }
+
+    pub fn read(path: &Path) -> eyre::Result<Self> {
+        let patch: Self = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        if patch.version != PATCH_VERSION {
+            eyre::bail!(
+                "unsupported patch version {} in {}, expected {PATCH_VERSION}",
+                patch.version,
+                path.display()
+            );
+        }
+        Ok(patch)
+    }
+
+    pub fn write(&self, path: &Path) -> eyre::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+impl AccountPatch {
+    /// Record an account that only exists on the left (`on_left`) or the right side. The
+    /// absent side reads as an empty account.
+    fn set_one_sided(&mut self, on_left: bool, balance: U256, nonce: u64, code_hash: B256) {
+        self.exists = Some(Change::one_sided(on_left, true, false));
+        self.balance = Some(Change::one_sided(on_left, balance, U256::ZERO));
+        self.nonce = Some(Change::one_sided(on_left, nonce, 0));
+        self.code_hash = Some(Change::one_sided(on_left, code_hash, KECCAK_EMPTY));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file path under the system temp directory, removed again on drop.
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("evm-diff-{name}-{}.json", std::process::id()));
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn patch() -> StatePatch {
+        let mut patch = StatePatch::new(7, Labels::default());
+        let account = patch.account(Address::repeat_byte(0x01));
+        account.balance = Some(Change {
+            left: U256::from(1),
+            right: U256::from(2),
+        });
+        account.storage.insert(
+            B256::with_last_byte(1),
+            Change {
+                left: None,
+                right: Some(U256::from(3)),
+            },
+        );
+        patch.account(Address::repeat_byte(0x02)).set_one_sided(
+            true,
+            U256::from(4),
+            1,
+            KECCAK_EMPTY,
+        );
+        patch.bytecodes.insert(
+            B256::repeat_byte(0xcc),
+            Change {
+                left: None,
+                right: Some(Bytes::from_static(&[0x00])),
+            },
+        );
+        patch
+    }
+
+    #[test]
+    fn written_patch_reads_back() {
+        let file = TempFile::new("patch-round-trip");
+        let patch = patch();
+        patch.write(&file.0).unwrap();
+        assert_eq!(StatePatch::read(&file.0).unwrap(), patch);
+    }
+
+    #[test]
+    fn rejects_another_version() {
+        let file = TempFile::new("patch-version");
+        let mut patch = patch();
+        patch.version = PATCH_VERSION + 1;
+        patch.write(&file.0).unwrap();
+        let error = StatePatch::read(&file.0).unwrap_err().to_string();
+        assert!(error.contains("unsupported patch version"), "{error}");
+    }
+}
@@ -0,0 +1,483 @@
+use crate::diff::merge_join;
+use crate::report::Divergence;
+use crate::types::{
+    BlockAndReceipts, LegacyReceipt, LegacyTxType, ReadPrecompileCalls, ReadPrecompileResult,
+    SystemTx,
+};
+use alloy_consensus::proofs::calculate_receipt_root;
+use alloy_consensus::{
+    BlockHeader, Eip658Value, Receipt, ReceiptEnvelope, Transaction, TxReceipt, TxType,
+};
+use alloy_primitives::{logs_bloom, Bloom, Log, B256};
+use std::collections::BTreeMap;
+use std::fmt::{self, Debug, Display};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// Decode a file of HyperEVM blocks with their receipts. Files ending in `.lz4` are
+/// decompressed first.
+pub fn read_blocks(path: &Path) -> eyre::Result<Vec<BlockAndReceipts>> {
+    let file = BufReader::new(File::open(path)?);
+    let mut reader: Box<dyn Read> = if path.extension().is_some_and(|ext| ext == "lz4") {
+        Box::new(lz4_flex::frame::FrameDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    Ok(rmp_serde::decode::from_read(&mut reader)?)
+}
+
+impl From<LegacyTxType> for TxType {
+    fn from(tx_type: LegacyTxType) -> Self {
+        match tx_type {
+            LegacyTxType::Legacy => Self::Legacy,
+            LegacyTxType::Eip2930 => Self::Eip2930,
+            LegacyTxType::Eip1559 => Self::Eip1559,
+            LegacyTxType::Eip4844 => Self::Eip4844,
+            LegacyTxType::Eip7702 => Self::Eip7702,
+        }
+    }
+}
+
+impl LegacyReceipt {
+    /// The receipt as committed to by the receipts root.
+    pub fn envelope(&self) -> ReceiptEnvelope {
+        let receipt = Receipt {
+            status: Eip658Value::Eip658(self.success),
+            cumulative_gas_used: self.cumulative_gas_used,
+            logs: self.logs.clone(),
+        };
+        ReceiptEnvelope::from_typed(self.tx_type.into(), receipt.with_bloom())
+    }
+}
+
+/// Receipts root and logs bloom of a block with `receipts`.
+pub fn receipts_root_and_bloom(receipts: &[LegacyReceipt]) -> (B256, Bloom) {
+    let envelopes: Vec<_> = receipts.iter().map(LegacyReceipt::envelope).collect();
+    let bloom = logs_bloom(receipts.iter().flat_map(|receipt| &receipt.logs));
+    (calculate_receipt_root(&envelopes), bloom)
+}
+
+/// Compare the receipts root and logs bloom committed to by `header` with those recomputed from
+/// `receipts`.
+pub fn diff_receipts_root(
+    header: &impl BlockHeader,
+    receipts: &[LegacyReceipt],
+) -> Vec<Divergence> {
+    let (root, bloom) = receipts_root_and_bloom(receipts);
+    let mut divergences = Vec::new();
+    if header.receipts_root() != root {
+        divergences.push(Divergence::Header {
+            number: header.number(),
+            field: "computed_receipts_root",
+            left: format!("{:?}", header.receipts_root()),
+            right: format!("{:?}", root),
+        });
+    }
+    if header.logs_bloom() != bloom {
+        divergences.push(Divergence::Header {
+            number: header.number(),
+            field: "computed_logs_bloom",
+            left: format!("{:?}", header.logs_bloom()),
+            right: format!("{:?}", bloom),
+        });
+    }
+    divergences
+}
+
+/// Compare receipts of block `number` pairwise. `offset` is the transaction index of the first
+/// receipt on both sides; a receipt present on one side only is reported as `presence`.
+pub fn diff_receipts<R: TxReceipt<Log = Log>>(
+    number: u64,
+    offset: usize,
+    left: &[R],
+    right: &[LegacyReceipt],
+) -> Vec<Divergence> {
+    let mut divergences = Vec::new();
+    let mut compare = |index: usize, field: &'static str, l: &dyn Debug, r: &dyn Debug| {
+        let (l, r) = (format!("{l:?}"), format!("{r:?}"));
+        if l != r {
+            divergences.push(Divergence::Receipt {
+                number,
+                index,
+                field,
+                left: l,
+                right: r,
+            });
+        }
+    };
+
+    for i in 0..left.len().max(right.len()) {
+        let index = offset + i;
+        let (l, r) = match (left.get(i), right.get(i)) {
+            (Some(l), Some(r)) => (l, r),
+            (l, r) => {
+                compare(index, "presence", &l.is_some(), &r.is_some());
+                continue;
+            }
+        };
+        compare(index, "success", &l.status(), &r.success);
+        compare(
+            index,
+            "cumulative_gas_used",
+            &l.cumulative_gas_used(),
+            &r.cumulative_gas_used,
+        );
+        if l.logs().len() != r.logs.len() {
+            compare(index, "log_count", &l.logs().len(), &r.logs.len());
+        } else if let Some((l, r)) = l.logs().iter().zip(&r.logs).find(|(l, r)| l != r) {
+            compare(index, "logs", l, r);
+        }
+    }
+    divergences
+}
+
+/// Number of system transactions at the start of a block. Reth stores them before the user
+/// transactions, recognizable by their zero gas price.
+pub fn system_tx_count<T: Transaction>(txs: &[T]) -> usize {
+    txs.iter()
+        .take_while(|tx| tx.max_fee_per_gas() == 0)
+        .count()
+}
+
+/// The fields of a system transaction that move funds, rendered for comparison.
+fn system_tx_fields(tx: &impl Transaction) -> [(&'static str, String); 3] {
+    [
+        ("recipient", format!("{:?}", tx.to())),
+        ("value", format!("{:?}", tx.value())),
+        ("input", format!("{:?}", tx.input())),
+    ]
+}
+
+/// Compare the system transactions of block `number` recorded in a block file with the leading
+/// system transactions and receipts reth stored for it.
+///
+/// A transaction that matches another position on the other side is reported as `ordering`
+/// rather than field by field. File receipts are only compared when present.
+pub fn diff_system_txs<T: Transaction, R: TxReceipt<Log = Log>>(
+    number: u64,
+    txs: &[T],
+    receipts: &[R],
+    system_txs: &[SystemTx],
+) -> Vec<Divergence> {
+    let left: Vec<_> = txs[..system_tx_count(txs)]
+        .iter()
+        .map(system_tx_fields)
+        .collect();
+    let right: Vec<_> = system_txs
+        .iter()
+        .map(|system_tx| system_tx_fields(&system_tx.tx))
+        .collect();
+    let mut divergences = Vec::new();
+    let mut push = |index: usize, field: &'static str, l: String, r: String| {
+        divergences.push(Divergence::SystemTx {
+            number,
+            index,
+            field,
+            left: l,
+            right: r,
+        });
+    };
+
+    for index in 0..left.len().max(right.len()) {
+        let (l, r) = match (left.get(index), right.get(index)) {
+            (Some(l), Some(r)) => (l, r),
+            (l, r) => {
+                push(
+                    index,
+                    "presence",
+                    l.is_some().to_string(),
+                    r.is_some().to_string(),
+                );
+                continue;
+            }
+        };
+        if l == r {
+            continue;
+        }
+        if let Some(position) = left.iter().position(|l| l == r) {
+            push(index, "ordering", position.to_string(), index.to_string());
+            continue;
+        }
+        for ((field, l), (_, r)) in l.iter().zip(r) {
+            if l != r {
+                push(index, field, l.clone(), r.clone());
+            }
+        }
+    }
+
+    for (index, system_tx) in system_txs.iter().enumerate() {
+        let (Some(receipt), Some(reth_receipt)) = (&system_tx.receipt, receipts.get(index)) else {
+            continue;
+        };
+        divergences.extend(diff_receipts(
+            number,
+            index,
+            std::slice::from_ref(reth_receipt),
+            std::slice::from_ref(receipt),
+        ));
+    }
+    divergences
+}
+
+impl Display for ReadPrecompileResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ok { gas_used, bytes } => write!(f, "ok, gas used {gas_used}, output {bytes}"),
+            Self::OutOfGas => f.write_str("out of gas"),
+            Self::Error => f.write_str("error"),
+            Self::UnexpectedError => f.write_str("unexpected error"),
+        }
+    }
+}
+
+/// Compare the read precompile calls of block `number`, matched by precompile address, input
+/// and gas limit.
+pub fn diff_precompile_calls(
+    number: u64,
+    left: &ReadPrecompileCalls,
+    right: &ReadPrecompileCalls,
+) -> eyre::Result<Vec<Divergence>> {
+    let index = |calls: &ReadPrecompileCalls| -> BTreeMap<_, _> {
+        calls
+            .iter()
+            .flat_map(|(address, calls)| {
+                calls
+                    .iter()
+                    .map(move |(input, result)| ((*address, input.clone()), result.clone()))
+            })
+            .collect()
+    };
+    let (left, right) = (index(left), index(right));
+    let mut divergences = Vec::new();
+    for entry in merge_join(left.into_iter().map(Ok), right.into_iter().map(Ok)) {
+        let ((address, input), left, right) = entry?;
+        if left != right {
+            divergences.push(Divergence::PrecompileCall {
+                number,
+                address,
+                input: input.input,
+                gas_limit: input.gas_limit,
+                left: left.map(|result| result.to_string()),
+                right: right.map(|result| result.to_string()),
+            });
+        }
+    }
+    Ok(divergences)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy_consensus::constants::EMPTY_ROOT_HASH;
+    use alloy_consensus::{Header, TxLegacy};
+    use alloy_primitives::{b256, Address, BloomInput, LogData, TxKind, U256};
+
+    fn tx(gas_price: u128, to: u8, value: u64) -> reth_primitives::Transaction {
+        reth_primitives::Transaction::Legacy(TxLegacy {
+            gas_price,
+            to: TxKind::Call(Address::repeat_byte(to)),
+            value: U256::from(value),
+            ..Default::default()
+        })
+    }
+
+    fn system_tx(to: u8, value: u64) -> SystemTx {
+        SystemTx {
+            tx: tx(0, to, value),
+            receipt: None,
+        }
+    }
+
+    fn system_tx_divergence(index: usize, field: &'static str, l: &str, r: &str) -> Divergence {
+        Divergence::SystemTx {
+            number: 1,
+            index,
+            field,
+            left: l.to_string(),
+            right: r.to_string(),
+        }
+    }
+
+    fn diff(txs: &[reth_primitives::Transaction], system_txs: &[SystemTx]) -> Vec<Divergence> {
+        diff_system_txs::<_, Receipt>(1, txs, &[], system_txs)
+    }
+
+    #[test]
+    fn counts_leading_zero_fee_txs() {
+        let txs = [tx(0, 1, 1), tx(0, 2, 1), tx(1, 3, 1), tx(0, 4, 1)];
+        assert_eq!(system_tx_count(&txs), 2);
+        assert_eq!(system_tx_count(&txs[2..]), 0);
+    }
+
+    #[test]
+    fn matching_system_txs_agree() {
+        let txs = [tx(0, 1, 1), tx(0, 2, 2), tx(1, 3, 3)];
+        assert_eq!(diff(&txs, &[system_tx(1, 1), system_tx(2, 2)]), []);
+    }
+
+    #[test]
+    fn reordered_pair_is_reported_as_ordering() {
+        let txs = [tx(0, 1, 1), tx(0, 2, 2)];
+        assert_eq!(
+            diff(&txs, &[system_tx(2, 2), system_tx(1, 1)]),
+            [
+                system_tx_divergence(0, "ordering", "1", "0"),
+                system_tx_divergence(1, "ordering", "0", "1"),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_and_extra_system_txs_are_reported_as_presence() {
+        let txs = [tx(0, 1, 1), tx(1, 2, 2)];
+        assert_eq!(
+            diff(&txs, &[system_tx(1, 1), system_tx(2, 2)]),
+            [system_tx_divergence(1, "presence", "false", "true")]
+        );
+        let txs = [tx(0, 1, 1), tx(0, 2, 2)];
+        assert_eq!(
+            diff(&txs, &[system_tx(1, 1)]),
+            [system_tx_divergence(1, "presence", "true", "false")]
+        );
+    }
+
+    #[test]
+    fn value_and_recipient_mismatches_are_reported_per_field() {
+        let divergences = diff(&[tx(0, 1, 1)], &[system_tx(1, 2)]);
+        assert_eq!(divergences.len(), 1);
+        assert!(matches!(
+            divergences[0],
+            Divergence::SystemTx {
+                index: 0,
+                field: "value",
+                ..
+            }
+        ));
+
+        let divergences = diff(&[tx(0, 1, 1)], &[system_tx(2, 1)]);
+        assert_eq!(divergences.len(), 1);
+        assert!(matches!(
+            divergences[0],
+            Divergence::SystemTx {
+                index: 0,
+                field: "recipient",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn system_tx_receipts_are_compared() {
+        let mut system_tx = system_tx(1, 1);
+        system_tx.receipt = Some(LegacyReceipt {
+            tx_type: LegacyTxType::Legacy,
+            success: true,
+            cumulative_gas_used: 21_000,
+            logs: Vec::new(),
+        });
+        let reth_receipt = Receipt {
+            status: Eip658Value::Eip658(true),
+            cumulative_gas_used: 20_000,
+            logs: Vec::new(),
+        };
+        assert_eq!(
+            diff_system_txs(1, &[tx(0, 1, 1)], &[reth_receipt], &[system_tx]),
+            [Divergence::Receipt {
+                number: 1,
+                index: 0,
+                field: "cumulative_gas_used",
+                left: "20000".to_string(),
+                right: "21000".to_string(),
+            }]
+        );
+    }
+
+    fn receipt(cumulative_gas_used: u64, logs: Vec<Log>) -> LegacyReceipt {
+        LegacyReceipt {
+            tx_type: LegacyTxType::Legacy,
+            success: true,
+            cumulative_gas_used,
+            logs,
+        }
+    }
+
+    fn log(address: Address) -> Log {
+        Log {
+            address,
+            data: LogData::new_unchecked(Vec::new(), Default::default()),
+        }
+    }
+
+    #[test]
+    fn receipts_root_of_a_single_transfer() {
+        let (root, bloom) = receipts_root_and_bloom(&[receipt(21_000, Vec::new())]);
+        assert_eq!(
+            root,
+            b256!("056b23fbba480696b65fe5a59b8f2148a1299103c4f57df839233af2cf4ca2d2")
+        );
+        assert_eq!(bloom, Bloom::ZERO);
+        assert_eq!(receipts_root_and_bloom(&[]), (EMPTY_ROOT_HASH, Bloom::ZERO));
+    }
+
+    #[test]
+    fn bloom_covers_log_addresses() {
+        let address = Address::repeat_byte(0x01);
+        let (_, bloom) = receipts_root_and_bloom(&[receipt(21_000, vec![log(address)])]);
+        assert!(bloom.contains_input(BloomInput::Raw(address.as_slice())));
+    }
+
+    #[test]
+    fn header_root_and_bloom_are_checked() {
+        let receipts = [receipt(21_000, vec![log(Address::repeat_byte(0x01))])];
+        let (receipts_root, logs_bloom) = receipts_root_and_bloom(&receipts);
+        let header = Header {
+            number: 1,
+            receipts_root,
+            logs_bloom,
+            ..Default::default()
+        };
+        assert_eq!(diff_receipts_root(&header, &receipts), []);
+
+        let fields: Vec<_> = diff_receipts_root(&Header::default(), &receipts)
+            .into_iter()
+            .map(|divergence| match divergence {
+                Divergence::Header { field, .. } => field,
+                divergence => panic!("unexpected {divergence:?}"),
+            })
+            .collect();
+        assert_eq!(fields, ["computed_receipts_root", "computed_logs_bloom"]);
+    }
+
+    #[test]
+    fn receipts_present_on_one_side_are_reported_as_presence() {
+        let left = [receipt(21_000, Vec::new()).envelope()];
+        let right = [receipt(21_000, Vec::new()), receipt(42_000, Vec::new())];
+        assert_eq!(
+            diff_receipts(1, 3, &left, &right),
+            [Divergence::Receipt {
+                number: 1,
+                index: 4,
+                field: "presence",
+                left: "false".to_string(),
+                right: "true".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn differing_log_counts_are_reported_once() {
+        let left = [receipt(21_000, Vec::new()).envelope()];
+        let right = [receipt(21_000, vec![log(Address::repeat_byte(0x01))])];
+        assert_eq!(
+            diff_receipts(1, 0, &left, &right),
+            [Divergence::Receipt {
+                number: 1,
+                index: 0,
+                field: "log_count",
+                left: "0".to_string(),
+                right: "1".to_string(),
+            }]
+        );
+    }
+}
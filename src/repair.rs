@@ -0,0 +1,331 @@
+use crate::patch::StatePatch;
+use crate::source::StateSource;
+use crate::types::DbAccountInfo;
+use alloy_consensus::constants::KECCAK_EMPTY;
+use alloy_primitives::{Address, Bytes, B256, U256};
+use reth_db::cursor::{DbCursorRW, DbDupCursorRO};
+use reth_db::tables;
+use reth_db::transaction::{DbTx, DbTxMut};
+use reth_primitives::{Account, Bytecode, StorageEntry};
+use reth_provider::{ProviderResult, StageCheckpointWriter};
+use reth_stages_types::{StageCheckpoint, StageId};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Write};
+
+/// One write to reth's plain state tables, with the value it replaces so it can be undone.
+/// `None` means the entry is absent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum StateWrite {
+    Account {
+        address: Address,
+        old: Option<DbAccountInfo>,
+        new: Option<DbAccountInfo>,
+    },
+    Storage {
+        address: Address,
+        slot: B256,
+        old: Option<U256>,
+        new: Option<U256>,
+    },
+    Bytecode {
+        code_hash: B256,
+        old: Option<Bytes>,
+        new: Bytes,
+    },
+}
+
+impl StateWrite {
+    /// Write a one line preview of this write.
+    pub fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Self::Account { address, old, new } => {
+                writeln!(out, "account {}: {:?} -> {:?}", address, old, new)
+            }
+            Self::Storage {
+                address,
+                slot,
+                old,
+                new,
+            } => {
+                writeln!(
+                    out,
+                    "storage {} {:#x}: {:?} -> {:?}",
+                    address, slot, old, new
+                )
+            }
+            Self::Bytecode {
+                code_hash,
+                old,
+                new,
+            } => writeln!(
+                out,
+                "bytecode {:#x}: {} -> {} bytes",
+                code_hash,
+                old.as_ref().map_or(0, |old| old.len()),
+                new.len()
+            ),
+        }
+    }
+}
+
+/// Turn `patch` into writes to reth's plain state, after checking that `reth` still holds the
+/// left values recorded in the patch. A stale patch is rejected with every mismatch listed.
+///
+/// Bytecode missing on the right is left in place, since unreferenced code is harmless.
+pub fn plan_repair(patch: &StatePatch, reth: &dyn StateSource) -> eyre::Result<Vec<StateWrite>> {
+    let mut writes = Vec::new();
+    let mut stale = Vec::new();
+    let mut check = |what: String, expected: String, found: String| {
+        if expected != found {
+            stale.push(format!(
+                "{what}: patch expects {expected}, reth has {found}"
+            ));
+        }
+    };
+
+    for (address, account) in &patch.accounts {
+        let address = *address;
+        let old = reth.account(address)?;
+        let current = old.clone().unwrap_or_default();
+        let mut new = current.clone();
+        if let Some(exists) = account.exists {
+            let found = old.as_ref().is_some_and(|info| !info.is_empty());
+            check(
+                format!("existence of {address}"),
+                exists.left.to_string(),
+                found.to_string(),
+            );
+        }
+        if let Some(balance) = account.balance {
+            check(
+                format!("balance of {address}"),
+                balance.left.to_string(),
+                current.balance.to_string(),
+            );
+            new.balance = balance.right;
+        }
+        if let Some(nonce) = account.nonce {
+            check(
+                format!("nonce of {address}"),
+                nonce.left.to_string(),
+                current.nonce.to_string(),
+            );
+            new.nonce = nonce.right;
+        }
+        if let Some(code_hash) = account.code_hash {
+            check(
+                format!("code hash of {address}"),
+                code_hash.left.to_string(),
+                current.code_hash.to_string(),
+            );
+            new.code_hash = code_hash.right;
+        }
+        let changed = account.exists.is_some()
+            || account.balance.is_some()
+            || account.nonce.is_some()
+            || account.code_hash.is_some();
+        if changed {
+            let exists = account.exists.map_or(old.is_some(), |exists| exists.right);
+            writes.push(StateWrite::Account {
+                address,
+                old,
+                new: exists.then_some(new),
+            });
+        }
+
+        if account.storage.is_empty() {
+            continue;
+        }
+        let storage = reth.storage(address)?;
+        for (slot, change) in &account.storage {
+            let old = storage.get(slot).copied();
+            check(
+                format!("slot {slot:#x} of {address}"),
+                format!("{:?}", change.left),
+                format!("{old:?}"),
+            );
+            writes.push(StateWrite::Storage {
+                address,
+                slot: *slot,
+                old,
+                new: change.right,
+            });
+        }
+    }
+
+    for (code_hash, change) in &patch.bytecodes {
+        let Some(new) = change.right.clone() else {
+            continue;
+        };
+        let old = reth.bytecode(*code_hash)?;
+        check(
+            format!("bytecode {code_hash:#x}"),
+            format!("{:?}", change.left),
+            format!("{old:?}"),
+        );
+        writes.push(StateWrite::Bytecode {
+            code_hash: *code_hash,
+            old,
+            new,
+        });
+    }
+
+    if !stale.is_empty() {
+        eyre::bail!("patch no longer matches reth:\n{}", stale.join("\n"));
+    }
+    Ok(writes)
+}
+
+impl From<&DbAccountInfo> for Account {
+    fn from(info: &DbAccountInfo) -> Self {
+        Self {
+            nonce: info.nonce,
+            balance: info.balance,
+            bytecode_hash: (info.code_hash != KECCAK_EMPTY).then_some(info.code_hash),
+        }
+    }
+}
+
+/// Apply `writes` to the plain state tables. Only the `new` values are used.
+pub fn apply_repair<TX: DbTx + DbTxMut>(tx: &TX, writes: &[StateWrite]) -> ProviderResult<()> {
+    let mut storage = tx.cursor_dup_write::<tables::PlainStorageState>()?;
+    for write in writes {
+        match write {
+            StateWrite::Account { address, new, .. } => match new {
+                Some(new) => tx.put::<tables::PlainAccountState>(*address, new.into())?,
+                None => {
+                    tx.delete::<tables::PlainAccountState>(*address, None)?;
+                }
+            },
+            StateWrite::Storage {
+                address, slot, new, ..
+            } => {
+                if storage
+                    .seek_by_key_subkey(*address, *slot)?
+                    .is_some_and(|entry| entry.key == *slot)
+                {
+                    storage.delete_current()?;
+                }
+                if let Some(value) = new.filter(|value| !value.is_zero()) {
+                    storage.upsert(*address, &StorageEntry { key: *slot, value })?;
+                }
+            }
+            StateWrite::Bytecode { code_hash, new, .. } => {
+                tx.put::<tables::Bytecodes>(*code_hash, Bytecode::new_raw(new.clone()))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Stages that rebuild the hashed state and the state trie from the plain state tables.
+pub const TRIE_STAGES: [StageId; 3] = [
+    StageId::AccountHashing,
+    StageId::StorageHashing,
+    StageId::MerkleExecute,
+];
+
+/// Reset the checkpoints of [`TRIE_STAGES`] so the node regenerates the hashed state and trie
+/// from the repaired plain state on its next start.
+pub fn reset_trie_stages(provider: &impl StageCheckpointWriter) -> ProviderResult<()> {
+    for stage in TRIE_STAGES {
+        provider.save_stage_checkpoint(stage, StageCheckpoint::new(0))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abci::InMemorySource;
+    use crate::diff::StateDiffer;
+    use crate::report::Labels;
+
+    const ADDRESS: Address = Address::repeat_byte(0xaa);
+
+    fn info(balance: u64) -> DbAccountInfo {
+        DbAccountInfo {
+            balance: U256::from(balance),
+            ..Default::default()
+        }
+    }
+
+    fn slots() -> [(B256, U256); 2] {
+        [
+            (B256::with_last_byte(1), U256::from(10)),
+            (B256::with_last_byte(2), U256::from(20)),
+        ]
+    }
+
+    /// The patch that turns `reth` into `abci`, built the way `repair` builds it.
+    fn patch(reth: &InMemorySource, abci: &InMemorySource) -> StatePatch {
+        let divergences: Vec<_> = StateDiffer::new(reth, abci)
+            .divergences()
+            .collect::<eyre::Result<_>>()
+            .unwrap();
+        StatePatch::from_divergences(1, Labels::default(), &divergences, reth, abci).unwrap()
+    }
+
+    #[test]
+    fn rejects_stale_patch() {
+        let reth = InMemorySource::default().with_account(ADDRESS, info(1), &[]);
+        let abci = InMemorySource::default().with_account(ADDRESS, info(2), &[]);
+        let patch = patch(&reth, &abci);
+
+        let moved = InMemorySource::default().with_account(ADDRESS, info(3), &[]);
+        let error = plan_repair(&patch, &moved).unwrap_err().to_string();
+        assert!(error.contains("patch no longer matches reth"), "{error}");
+        assert!(error.contains("balance of"), "{error}");
+        assert_eq!(plan_repair(&patch, &reth).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn creates_missing_account_with_slots() {
+        let reth = InMemorySource::default();
+        let abci = InMemorySource::default().with_account(ADDRESS, info(7), &slots());
+        let writes = plan_repair(&patch(&reth, &abci), &reth).unwrap();
+
+        let mut expected = vec![StateWrite::Account {
+            address: ADDRESS,
+            old: None,
+            new: Some(info(7)),
+        }];
+        expected.extend(slots().map(|(slot, value)| StateWrite::Storage {
+            address: ADDRESS,
+            slot,
+            old: None,
+            new: Some(value),
+        }));
+        assert_eq!(writes, expected);
+    }
+
+    #[test]
+    fn deletes_extra_account_with_slots() {
+        let reth = InMemorySource::default().with_account(ADDRESS, info(7), &slots());
+        let abci = InMemorySource::default();
+        let writes = plan_repair(&patch(&reth, &abci), &reth).unwrap();
+
+        let mut expected = vec![StateWrite::Account {
+            address: ADDRESS,
+            old: Some(info(7)),
+            new: None,
+        }];
+        expected.extend(slots().map(|(slot, value)| StateWrite::Storage {
+            address: ADDRESS,
+            slot,
+            old: Some(value),
+            new: None,
+        }));
+        assert_eq!(writes, expected);
+    }
+
+    #[test]
+    fn skips_bytecode_missing_on_the_right() {
+        let reth = InMemorySource::default().with_contract(Bytes::from_static(&[0x60, 0x00]));
+        let abci = InMemorySource::default();
+        let patch = patch(&reth, &abci);
+        assert_eq!(patch.bytecodes.len(), 1);
+        assert!(plan_repair(&patch, &reth).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,73 @@
+use crate::diff::StateDiffer;
+use crate::report::Divergence;
+use crate::source::StateSource;
+use crate::types::DbAccountInfo;
+use alloy_primitives::{B256, U256};
+use revm::database::BundleState;
+use revm::state::AccountInfo;
+use std::collections::BTreeMap;
+
+impl From<&AccountInfo> for DbAccountInfo {
+    fn from(info: &AccountInfo) -> Self {
+        Self {
+            balance: info.balance,
+            nonce: info.nonce,
+            code_hash: info.code_hash,
+        }
+    }
+}
+
+/// Compare the accounts, slots and bytecodes written by re-executing a block against `other`.
+///
+/// `left` of every divergence is the replayed value. Only keys touched by the block are checked,
+/// so the state before the block is assumed to agree; accounts destroyed by the block have all
+/// of their slots on `other` checked.
+pub fn diff_post_state(
+    bundle: &BundleState,
+    other: &dyn StateSource,
+) -> eyre::Result<Vec<Divergence>> {
+    let mut divergences = Vec::new();
+    let accounts: BTreeMap<_, _> = bundle.state.iter().collect();
+    for (address, account) in accounts {
+        let replayed = account.info.as_ref().map(DbAccountInfo::from);
+        let expected = other.account(*address)?;
+        divergences.extend(StateDiffer::diff_account(
+            *address,
+            replayed.as_ref(),
+            expected.as_ref(),
+        ));
+
+        let storage = other.storage(*address)?;
+        let mut slots: BTreeMap<B256, Option<U256>> = if account.was_destroyed() {
+            storage.keys().map(|slot| (*slot, None)).collect()
+        } else {
+            BTreeMap::new()
+        };
+        for (slot, value) in &account.storage {
+            let value = Some(value.present_value).filter(|value| !value.is_zero());
+            slots.insert(B256::from(*slot), value);
+        }
+        for (slot, left) in slots {
+            let right = storage.get(&slot).copied();
+            if left != right {
+                divergences.push(Divergence::Storage {
+                    address: *address,
+                    slot,
+                    left,
+                    right,
+                });
+            }
+        }
+    }
+
+    let contracts: BTreeMap<_, _> = bundle.contracts.iter().collect();
+    for (code_hash, code) in contracts {
+        let expected = other.bytecode(*code_hash)?;
+        divergences.extend(StateDiffer::diff_contract(
+            *code_hash,
+            Some(&code.original_bytes()),
+            expected.as_ref(),
+        ));
+    }
+    Ok(divergences)
+}
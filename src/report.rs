@@ -0,0 +1,502 @@
+use alloy_primitives::{Address, Bytes, B256, U256};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// A single difference between two state sources. `left` is the reference side (reth in the
+/// default diff) and `right` the side being checked against it (abci).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Divergence {
+    Balance {
+        address: Address,
+        left: U256,
+        right: U256,
+    },
+    Nonce {
+        address: Address,
+        left: u64,
+        right: u64,
+    },
+    CodeHash {
+        address: Address,
+        left: B256,
+        right: B256,
+    },
+    /// A storage slot differs. `None` means the slot is unset (zero) on that side.
+    Storage {
+        address: Address,
+        slot: B256,
+        left: Option<U256>,
+        right: Option<U256>,
+    },
+    /// The account exists on the right but not on the left.
+    MissingAccount {
+        address: Address,
+        balance: U256,
+        nonce: u64,
+        code_hash: B256,
+    },
+    /// The account exists on the left but not on the right.
+    ExtraAccount {
+        address: Address,
+        balance: U256,
+        nonce: u64,
+        code_hash: B256,
+    },
+    /// Bytecode stored on the right is missing on the left.
+    MissingCode { code_hash: B256 },
+    /// Bytecode stored on the left is missing on the right.
+    ExtraCode { code_hash: B256 },
+    /// Both sides store bytecode under the same hash, but the bytes differ.
+    Bytecode { code_hash: B256 },
+    /// A block header field differs. Values are rendered with their `Debug` form.
+    Header {
+        number: u64,
+        field: &'static str,
+        left: String,
+        right: String,
+    },
+    /// A field of the receipt of transaction `index` in block `number` differs. Values are
+    /// rendered with their `Debug` form.
+    Receipt {
+        number: u64,
+        index: usize,
+        field: &'static str,
+        left: String,
+        right: String,
+    },
+    /// A system transaction of block `number` differs, `index` being its position in the block.
+    SystemTx {
+        number: u64,
+        index: usize,
+        field: &'static str,
+        left: String,
+        right: String,
+    },
+    /// A read precompile call of block `number` returned different results. `None` means the
+    /// side did not record the call.
+    PrecompileCall {
+        number: u64,
+        address: Address,
+        input: Bytes,
+        gas_limit: u64,
+        left: Option<String>,
+        right: Option<String>,
+    },
+    /// The state root of block `number` differs from the root computed over the other side.
+    StateRoot {
+        number: u64,
+        left: B256,
+        right: B256,
+    },
+    /// The hash recorded for block `number` differs. `None` means the side has no entry.
+    BlockHash {
+        number: u64,
+        left: Option<B256>,
+        right: Option<B256>,
+    },
+}
+
+impl Divergence {
+    /// Short category name, used for the summary table and the `kind` JSON tag.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Balance { .. } => "balance",
+            Self::Nonce { .. } => "nonce",
+            Self::CodeHash { .. } => "code_hash",
+            Self::Storage { .. } => "storage",
+            Self::MissingAccount { .. } => "missing_account",
+            Self::ExtraAccount { .. } => "extra_account",
+            Self::MissingCode { .. } => "missing_code",
+            Self::ExtraCode { .. } => "extra_code",
+            Self::Bytecode { .. } => "bytecode",
+            Self::Header { .. } => "header",
+            Self::Receipt { .. } => "receipt",
+            Self::SystemTx { .. } => "system_tx",
+            Self::PrecompileCall { .. } => "precompile_call",
+            Self::StateRoot { .. } => "state_root",
+            Self::BlockHash { .. } => "block_hash",
+        }
+    }
+
+    /// Write the human readable (ANSI colored) form of this divergence.
+    pub fn write_text<W: Write>(
+        &self,
+        out: &mut W,
+        block_number: u64,
+        labels: &Labels,
+    ) -> io::Result<()> {
+        let Labels { left: l, right: r } = labels;
+        match self {
+            Self::Balance { address, left, right } => {
+                writeln!(out, "\x1b[1mBalance mismatch for {}\x1b[0m (block {})", address, block_number)?;
+                writeln!(out, "  \x1b[31m- {}: {}\x1b[0m", l, left)?;
+                writeln!(out, "  \x1b[32m+ {}: {}\x1b[0m", r, right)
+            }
+            Self::Nonce { address, left, right } => {
+                writeln!(out, "\x1b[1mNonce mismatch for {}\x1b[0m", address)?;
+                writeln!(out, "  \x1b[31m- {}: {}\x1b[0m", l, left)?;
+                writeln!(out, "  \x1b[32m+ {}: {}\x1b[0m", r, right)
+            }
+            Self::CodeHash { address, left, right } => {
+                writeln!(out, "\x1b[1mCode hash mismatch for {}\x1b[0m (block {})", address, block_number)?;
+                writeln!(out, "  \x1b[31m- {}: {:#x}\x1b[0m", l, left)?;
+                writeln!(out, "  \x1b[32m+ {}: {:#x}\x1b[0m", r, right)
+            }
+            Self::Storage { address, slot, left, right } => {
+                writeln!(out, "\x1b[1mStorage mismatch for {}\x1b[0m", address)?;
+                match (left, right) {
+                    (Some(left), Some(right)) => {
+                        writeln!(out, "  \x1b[33m~ {:#x}\x1b[0m", slot)?;
+                        writeln!(out, "    \x1b[31m- {}: {:#x}\x1b[0m", l, left)?;
+                        writeln!(out, "    \x1b[32m+ {}: {:#x}\x1b[0m", r, right)
+                    }
+                    (Some(left), None) => {
+                        writeln!(out, "  \x1b[31m- {:#x} = {:#x}\x1b[0m (only in {})", slot, left, l)
+                    }
+                    (None, Some(right)) => {
+                        writeln!(out, "  \x1b[32m+ {:#x} = {:#x}\x1b[0m (only in {})", slot, right, r)
+                    }
+                    (None, None) => Ok(()),
+                }
+            }
+            Self::MissingAccount { address, balance, nonce, code_hash } => {
+                writeln!(out, "\x1b[1mAccount {} not found in {} but exists in {}\x1b[0m", address, l, r)?;
+                writeln!(out, "  balance: {}, nonce: {}, code_hash: {:#x}", balance, nonce, code_hash)
+            }
+            Self::ExtraAccount { address, balance, nonce, code_hash } => {
+                writeln!(out, "\x1b[1mAccount {} not found in {} but exists in {}\x1b[0m", address, r, l)?;
+                writeln!(out, "  balance: {}, nonce: {}, code_hash: {:#x}", balance, nonce, code_hash)
+            }
+            Self::MissingCode { code_hash } => {
+                writeln!(out, "\x1b[31mCode not found in {}: {:#x}\x1b[0m", l, code_hash)
+            }
+            Self::ExtraCode { code_hash } => {
+                writeln!(out, "\x1b[31mCode not found in {}: {:#x}\x1b[0m", r, code_hash)
+            }
+            Self::Bytecode { code_hash } => {
+                writeln!(out, "\x1b[1mBytecode mismatch for hash {:#x}\x1b[0m", code_hash)
+            }
+            Self::Header { number, field, left, right } => {
+                writeln!(out, "\x1b[1mHeader {} mismatch\x1b[0m (block {})", field, number)?;
+                writeln!(out, "  \x1b[31m- {}: {}\x1b[0m", l, left)?;
+                writeln!(out, "  \x1b[32m+ {}: {}\x1b[0m", r, right)
+            }
+            Self::Receipt { number, index, field, left, right } => {
+                writeln!(out, "\x1b[1mReceipt {} mismatch for tx {}\x1b[0m (block {})", field, index, number)?;
+                writeln!(out, "  \x1b[31m- {}: {}\x1b[0m", l, left)?;
+                writeln!(out, "  \x1b[32m+ {}: {}\x1b[0m", r, right)
+            }
+            Self::SystemTx { number, index, field, left, right } => {
+                writeln!(out, "\x1b[1mSystem tx {} mismatch for tx {}\x1b[0m (block {})", field, index, number)?;
+                writeln!(out, "  \x1b[31m- {}: {}\x1b[0m", l, left)?;
+                writeln!(out, "  \x1b[32m+ {}: {}\x1b[0m", r, right)
+            }
+            Self::PrecompileCall { number, address, input, gas_limit, left, right } => {
+                writeln!(out, "\x1b[1mPrecompile call mismatch for {}\x1b[0m (block {})", address, number)?;
+                writeln!(out, "  input: {}, gas limit: {}", input, gas_limit)?;
+                match (left, right) {
+                    (Some(left), Some(right)) => {
+                        writeln!(out, "  \x1b[31m- {}: {}\x1b[0m", l, left)?;
+                        writeln!(out, "  \x1b[32m+ {}: {}\x1b[0m", r, right)
+                    }
+                    (Some(left), None) => writeln!(out, "  \x1b[31m- {}\x1b[0m (only in {})", left, l),
+                    (None, Some(right)) => writeln!(out, "  \x1b[32m+ {}\x1b[0m (only in {})", right, r),
+                    (None, None) => Ok(()),
+                }
+            }
+            Self::StateRoot { number, left, right } => {
+                writeln!(out, "\x1b[1mState root mismatch\x1b[0m (block {})", number)?;
+                writeln!(out, "  \x1b[31m- {}: {:#x}\x1b[0m", l, left)?;
+                writeln!(out, "  \x1b[32m+ {}: {:#x}\x1b[0m", r, right)
+            }
+            Self::BlockHash { number, left, right } => {
+                writeln!(out, "\x1b[1mBlock hash mismatch for block {}\x1b[0m", number)?;
+                match (left, right) {
+                    (Some(left), Some(right)) => {
+                        writeln!(out, "  \x1b[31m- {}: {:#x}\x1b[0m", l, left)?;
+                        writeln!(out, "  \x1b[32m+ {}: {:#x}\x1b[0m", r, right)
+                    }
+                    (Some(left), None) => writeln!(out, "  \x1b[31m- {:#x}\x1b[0m (only in {})", left, l),
+                    (None, Some(right)) => writeln!(out, "  \x1b[32m+ {:#x}\x1b[0m (only in {})", right, r),
+                    (None, None) => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+/// Names of the two compared sources, as shown in reports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Labels {
+    pub left: String,
+    pub right: String,
+}
+
+impl Labels {
+    pub fn new(left: impl Into<String>, right: impl Into<String>) -> Self {
+        Self {
+            left: left.into(),
+            right: right.into(),
+        }
+    }
+}
+
+impl Default for Labels {
+    fn default() -> Self {
+        Self::new("reth", "abci")
+    }
+}
+
+/// All divergences found while comparing the state at `block_number`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DiffReport {
+    pub block_number: u64,
+    #[serde(flatten)]
+    pub labels: Labels,
+    pub divergences: Vec<ReportedDivergence>,
+    pub summary: Summary,
+}
+
+/// A divergence in a [`DiffReport`], with its own labels if it compares a different pair of
+/// sources than the report.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportedDivergence {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub labels: Option<Labels>,
+    #[serde(flatten)]
+    pub divergence: Divergence,
+}
+
+/// Number of divergences found per category.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Summary {
+    pub counts: BTreeMap<&'static str, usize>,
+    /// Whether the diff stopped early because `--max-mismatches` was reached.
+    pub truncated: bool,
+}
+
+impl Summary {
+    pub fn record(&mut self, divergence: &Divergence) {
+        *self.counts.entry(divergence.kind()).or_default() += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.values().sum()
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.total() == 0
+    }
+
+    pub fn write_table<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "\x1b[1m{:<16} {:>10}\x1b[0m", "category", "count")?;
+        for (kind, count) in &self.counts {
+            writeln!(out, "{:<16} {:>10}", kind, count)?;
+        }
+        writeln!(out, "{:<16} {:>10}", "total", self.total())?;
+        if self.truncated {
+            writeln!(out, "\x1b[33mstopped early: mismatch limit reached\x1b[0m")?;
+        }
+        Ok(())
+    }
+}
+
+/// One line of NDJSON output.
+#[derive(Serialize)]
+struct Record<'a> {
+    block_number: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    labels: Option<&'a Labels>,
+    #[serde(flatten)]
+    divergence: &'a Divergence,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// ANSI colored text, one block per divergence.
+    #[default]
+    Text,
+    /// A single `DiffReport` object written once the diff completes.
+    Json,
+    /// One JSON object per divergence, written as soon as it is found.
+    Ndjson,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err(format!(
+                "unknown format `{s}`, expected one of: text, json, ndjson"
+            )),
+        }
+    }
+}
+
+/// Writes divergences to `out` in the selected [`OutputFormat`].
+pub struct Reporter<W: Write> {
+    format: OutputFormat,
+    out: W,
+    report: DiffReport,
+    max_mismatches: Option<usize>,
+}
+
+impl<W: Write> Reporter<W> {
+    pub fn new(format: OutputFormat, block_number: u64, out: W) -> Self {
+        Self {
+            format,
+            out,
+            report: DiffReport {
+                block_number,
+                ..Default::default()
+            },
+            max_mismatches: None,
+        }
+    }
+
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.report.labels = labels;
+        self
+    }
+
+    /// Stop accepting divergences once `max` have been reported.
+    pub fn with_max_mismatches(mut self, max: Option<usize>) -> Self {
+        self.max_mismatches = max;
+        self
+    }
+
+    /// Whether the mismatch limit has been reached, so further divergences are dropped. The
+    /// summary is only marked truncated once one actually is.
+    pub fn limit_reached(&self) -> bool {
+        self.max_mismatches
+            .is_some_and(|max| self.report.summary.total() >= max)
+    }
+
+    pub fn block_number(&self) -> u64 {
+        self.report.block_number
+    }
+
+    pub fn labels(&self) -> &Labels {
+        &self.report.labels
+    }
+
+    pub fn summary(&self) -> &Summary {
+        &self.report.summary
+    }
+
+    pub fn report(&mut self, divergence: Divergence) -> io::Result<()> {
+        self.report_as(divergence, None)
+    }
+
+    /// Report a divergence between another pair of sources than the report's labels, e.g.
+    /// when one source is checked against two others in the same run.
+    pub fn report_labelled(&mut self, divergence: Divergence, labels: Labels) -> io::Result<()> {
+        self.report_as(divergence, Some(labels))
+    }
+
+    fn report_as(&mut self, divergence: Divergence, labels: Option<Labels>) -> io::Result<()> {
+        if self.limit_reached() {
+            self.report.summary.truncated = true;
+            return Ok(());
+        }
+        self.report.summary.record(&divergence);
+        let block_number = self.report.block_number;
+        match self.format {
+            OutputFormat::Text => {
+                let labels = labels.as_ref().unwrap_or(&self.report.labels);
+                divergence.write_text(&mut self.out, block_number, labels)
+            }
+            OutputFormat::Json => {
+                self.report
+                    .divergences
+                    .push(ReportedDivergence { labels, divergence });
+                Ok(())
+            }
+            OutputFormat::Ndjson => {
+                let record = Record {
+                    block_number,
+                    labels: labels.as_ref(),
+                    divergence: &divergence,
+                };
+                writeln!(self.out, "{}", serde_json::to_string(&record)?)
+            }
+        }
+    }
+
+    /// Flush any buffered output and return the summary. For [`OutputFormat::Json`] this
+    /// writes the full report.
+    pub fn finish(mut self) -> io::Result<Summary> {
+        if self.format == OutputFormat::Json {
+            writeln!(self.out, "{}", serde_json::to_string_pretty(&self.report)?)?;
+        }
+        self.out.flush()?;
+        Ok(self.report.summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report_roots(max: usize, count: u64) -> Summary {
+        let mut reporter =
+            Reporter::new(OutputFormat::Ndjson, 1, Vec::new()).with_max_mismatches(Some(max));
+        for number in 0..count {
+            reporter
+                .report(Divergence::StateRoot {
+                    number,
+                    left: B256::ZERO,
+                    right: B256::repeat_byte(1),
+                })
+                .unwrap();
+        }
+        reporter.finish().unwrap()
+    }
+
+    #[test]
+    fn exactly_max_mismatches_is_not_truncated() {
+        let summary = report_roots(2, 2);
+        assert_eq!(summary.total(), 2);
+        assert!(!summary.truncated);
+    }
+
+    #[test]
+    fn dropped_mismatch_truncates() {
+        let summary = report_roots(2, 3);
+        assert_eq!(summary.total(), 2);
+        assert!(summary.truncated);
+    }
+
+    #[test]
+    fn labelled_record_keeps_divergence_sides() {
+        let mut out = Vec::new();
+        let mut reporter = Reporter::new(OutputFormat::Ndjson, 1, &mut out);
+        reporter
+            .report_labelled(
+                Divergence::StateRoot {
+                    number: 1,
+                    left: B256::ZERO,
+                    right: B256::repeat_byte(1),
+                },
+                Labels::new("replay", "abci"),
+            )
+            .unwrap();
+        reporter.finish().unwrap();
+
+        let record: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(record["labels"]["left"], "replay");
+        assert_eq!(record["labels"]["right"], "abci");
+        assert_eq!(record["left"], serde_json::to_value(B256::ZERO).unwrap());
+        assert_eq!(
+            record["right"],
+            serde_json::to_value(B256::repeat_byte(1)).unwrap()
+        );
+    }
+}
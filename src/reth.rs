@@ -0,0 +1,532 @@
+use crate::diff::merge_join;
+use crate::source::{Shard, StateSource};
+use crate::types::DbAccountInfo;
+use alloy_primitives::{Address, Bytes, B256, U256};
+use reth_db::cursor::{DbCursorRO, DbDupCursorRO};
+use reth_db::models::storage_sharded_key::StorageShardedKey;
+use reth_db::models::{BlockNumberAddress, ShardedKey};
+use reth_db::table::Table;
+use reth_db::transaction::DbTx;
+use reth_db::{tables, DatabaseError};
+use reth_primitives::Account;
+use reth_provider::{DBProvider, ProviderResult, StateProviderBox, StorageRootProvider};
+use reth_trie::HashedStorage;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+/// Read all slots of `address` from `PlainStorageState`.
+pub fn plain_storage<P: DBProvider>(
+    provider: &P,
+    address: Address,
+) -> ProviderResult<BTreeMap<B256, U256>> {
+    let mut storage_cursor = provider
+        .tx_ref()
+        .cursor_dup_read::<tables::PlainStorageState>()?;
+    let mut storage = BTreeMap::new();
+
+    if let Some((_, first_entry)) = storage_cursor.seek_exact(address)? {
+        storage.insert(first_entry.key, first_entry.value);
+
+        while let Some((_, entry)) = storage_cursor.next_dup()? {
+            storage.insert(entry.key, entry.value);
+        }
+    }
+
+    Ok(storage)
+}
+
+/// Walk the keys of `shard` in order, starting at `start` and taking ownership of the cursor.
+pub(crate) fn walk_table<T: Table>(
+    mut cursor: impl DbCursorRO<T>,
+    start: T::Key,
+    shard: Shard,
+) -> impl Iterator<Item = Result<(T::Key, T::Value), DatabaseError>>
+where
+    T::Key: AsRef<[u8]>,
+{
+    let mut start = Some(start);
+    std::iter::from_fn(move || {
+        let entry = match start.take() {
+            Some(start) => cursor.seek(start),
+            None => cursor.next(),
+        };
+        entry.transpose()
+    })
+    .take_while(move |entry| match entry {
+        Ok((key, _)) => shard.contains(key.as_ref()),
+        Err(_) => true,
+    })
+}
+
+impl From<Account> for DbAccountInfo {
+    fn from(account: Account) -> Self {
+        Self {
+            balance: account.balance,
+            nonce: account.nonce,
+            code_hash: account.get_bytecode_hash(),
+        }
+    }
+}
+
+/// A block that modified an account or slot, with its value before and after that block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Write<V> {
+    pub block: u64,
+    pub before: V,
+    pub after: V,
+}
+
+/// Where a [`History`] stopped holding an expected value, as found by [`History::divergence`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryDivergence {
+    /// The value at the block is the expected one.
+    Matches,
+    /// The write at `block` replaced the expected value. `last_match` is the write that had
+    /// set it, or `None` if the value before the first write was expected.
+    DivergedAt { block: u64, last_match: Option<u64> },
+    /// No value held up to the block was the expected one.
+    NeverMatched,
+}
+
+/// Every write to one account or slot, oldest first, and its value in the plain state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct History<V> {
+    pub writes: Vec<Write<V>>,
+    pub current: V,
+}
+
+impl<V> History<V> {
+    fn new(blocks: Vec<u64>, befores: Vec<V>, current: V) -> Self
+    where
+        V: Clone,
+    {
+        let afters = befores.iter().skip(1).cloned().chain([current.clone()]);
+        let writes = blocks
+            .into_iter()
+            .zip(befores.iter().cloned().zip(afters))
+            .map(|(block, (before, after))| Write {
+                block,
+                before,
+                after,
+            })
+            .collect();
+        Self { writes, current }
+    }
+
+    /// Value held once `block` has been executed.
+    pub fn value_at(&self, block: u64) -> &V {
+        match self.writes.iter().rposition(|write| write.block <= block) {
+            Some(index) => &self.writes[index].after,
+            None => self
+                .writes
+                .first()
+                .map_or(&self.current, |write| &write.before),
+        }
+    }
+
+    /// Compare the value at `block` with `expected` and, if they differ, find the write up to
+    /// `block` that replaced the last value equal to `expected`. Writes after `block` are
+    /// ignored.
+    pub fn divergence(&self, block: u64, expected: &V) -> HistoryDivergence
+    where
+        V: PartialEq,
+    {
+        if self.value_at(block) == expected {
+            return HistoryDivergence::Matches;
+        }
+        let writes: Vec<_> = self
+            .writes
+            .iter()
+            .take_while(|write| write.block <= block)
+            .collect();
+        match writes.iter().rposition(|write| write.after == *expected) {
+            // The value at `block` isn't `expected`, so a later write up to `block` exists.
+            Some(index) => HistoryDivergence::DivergedAt {
+                block: writes[index + 1].block,
+                last_match: Some(writes[index].block),
+            },
+            None => match writes.first() {
+                Some(first) if first.before == *expected => HistoryDivergence::DivergedAt {
+                    block: first.block,
+                    last_match: None,
+                },
+                _ => HistoryDivergence::NeverMatched,
+            },
+        }
+    }
+
+    pub fn map<U>(self, f: impl Fn(V) -> U) -> History<U> {
+        History {
+            writes: self
+                .writes
+                .into_iter()
+                .map(|write| Write {
+                    block: write.block,
+                    before: f(write.before),
+                    after: f(write.after),
+                })
+                .collect(),
+            current: f(self.current),
+        }
+    }
+}
+
+/// Blocks that modified `address` according to `AccountsHistory`, with the account before and
+/// after each one taken from `AccountChangeSets`.
+///
+/// Relies on the history index stages having caught up with execution.
+pub fn account_history<P: DBProvider>(
+    provider: &P,
+    address: Address,
+) -> ProviderResult<History<Option<Account>>> {
+    let mut blocks = Vec::new();
+    let mut cursor = provider.tx_ref().cursor_read::<tables::AccountsHistory>()?;
+    for entry in cursor.walk(Some(ShardedKey::new(address, 0)))? {
+        let (key, list) = entry?;
+        if key.key != address {
+            break;
+        }
+        blocks.extend(list.iter());
+    }
+
+    let mut changesets = provider
+        .tx_ref()
+        .cursor_dup_read::<tables::AccountChangeSets>()?;
+    let mut befores = Vec::with_capacity(blocks.len());
+    for block in &blocks {
+        let change = changesets.seek_by_key_subkey(*block, address)?;
+        befores.push(
+            change
+                .filter(|change| change.address == address)
+                .and_then(|change| change.info),
+        );
+    }
+
+    let current = provider
+        .tx_ref()
+        .get::<tables::PlainAccountState>(address)?;
+    Ok(History::new(blocks, befores, current))
+}
+
+/// Blocks that modified `slot` of `address` according to `StoragesHistory`, with the value
+/// before and after each one taken from `StorageChangeSets`. Unset slots read as zero.
+pub fn storage_history<P: DBProvider>(
+    provider: &P,
+    address: Address,
+    slot: B256,
+) -> ProviderResult<History<U256>> {
+    let mut blocks = Vec::new();
+    let mut cursor = provider.tx_ref().cursor_read::<tables::StoragesHistory>()?;
+    for entry in cursor.walk(Some(StorageShardedKey::new(address, slot, 0)))? {
+        let (key, list) = entry?;
+        if key.address != address || key.sharded_key.key != slot {
+            break;
+        }
+        blocks.extend(list.iter());
+    }
+
+    let mut changesets = provider
+        .tx_ref()
+        .cursor_dup_read::<tables::StorageChangeSets>()?;
+    let mut befores = Vec::with_capacity(blocks.len());
+    for block in &blocks {
+        let change = changesets.seek_by_key_subkey(BlockNumberAddress((*block, address)), slot)?;
+        befores.push(
+            change
+                .filter(|change| change.key == slot)
+                .map_or(U256::ZERO, |change| change.value),
+        );
+    }
+
+    let current = provider
+        .tx_ref()
+        .cursor_dup_read::<tables::PlainStorageState>()?
+        .seek_by_key_subkey(address, slot)?
+        .filter(|entry| entry.key == slot)
+        .map_or(U256::ZERO, |entry| entry.value);
+    Ok(History::new(blocks, befores, current))
+}
+
+/// Values of the plain state tables at a past block, recovered by unwinding the changesets of
+/// every later block. `None` means the account did not exist at that block; a zero slot was
+/// unset.
+#[derive(Debug, Clone, Default)]
+pub struct ChangesetOverlay {
+    pub accounts: BTreeMap<Address, Option<Account>>,
+    pub storage: BTreeMap<(Address, B256), U256>,
+    /// Code hashes referenced by accounts at the block. `Bytecodes` is never unwound and keeps
+    /// code no account references any more, so only these of its entries are reported, at the
+    /// tip as well as below it. `None` without an overlay, which reports every entry.
+    pub code_hashes: Option<BTreeSet<B256>>,
+}
+
+impl ChangesetOverlay {
+    /// Collect the changesets of all blocks after `block`. The first change to a key after
+    /// `block` holds its value at `block`. Also walks the plain account table once for the
+    /// code hashes the accounts at `block` reference.
+    ///
+    /// The changesets of every later block are held in memory, so this is only cheap close to
+    /// the tip.
+    pub fn after<P: DBProvider>(provider: &P, block: u64) -> ProviderResult<Self> {
+        let mut overlay = Self::default();
+
+        let mut cursor = provider
+            .tx_ref()
+            .cursor_read::<tables::AccountChangeSets>()?;
+        for entry in cursor.walk(Some(block + 1))? {
+            let (_, change) = entry?;
+            overlay
+                .accounts
+                .entry(change.address)
+                .or_insert(change.info);
+        }
+
+        let mut cursor = provider
+            .tx_ref()
+            .cursor_read::<tables::StorageChangeSets>()?;
+        for entry in cursor.walk(Some(BlockNumberAddress((block + 1, Address::ZERO))))? {
+            let (key, change) = entry?;
+            overlay
+                .storage
+                .entry((key.address(), change.key))
+                .or_insert(change.value);
+        }
+
+        let mut code_hashes = BTreeSet::new();
+        let mut cursor = provider
+            .tx_ref()
+            .cursor_read::<tables::PlainAccountState>()?;
+        for entry in cursor.walk(None)? {
+            let (address, account) = entry?;
+            if !overlay.accounts.contains_key(&address) {
+                code_hashes.extend(account.bytecode_hash);
+            }
+        }
+        let unwound = overlay.accounts.values().flatten();
+        code_hashes.extend(unwound.filter_map(|account| account.bytecode_hash));
+        overlay.code_hashes = Some(code_hashes);
+
+        Ok(overlay)
+    }
+
+    fn accounts(
+        &self,
+        shard: Shard,
+    ) -> impl Iterator<Item = eyre::Result<(Address, Option<Account>)>> + '_ {
+        self.accounts
+            .range(shard.start_address()..)
+            .take_while(move |(address, _)| shard.contains(address.as_slice()))
+            .map(|(address, account)| Ok((*address, *account)))
+    }
+
+    fn storage(
+        &self,
+        shard: Shard,
+    ) -> impl Iterator<Item = eyre::Result<((Address, B256), Option<U256>)>> + '_ {
+        self.storage
+            .range((shard.start_address(), B256::ZERO)..)
+            .take_while(move |((address, _), _)| shard.contains(address.as_slice()))
+            .map(|(key, value)| Ok((*key, Some(*value).filter(|value| !value.is_zero()))))
+    }
+}
+
+/// Replace the entries of `plain` with those recorded in `overlay`, dropping keys the overlay
+/// marks as absent.
+fn apply_overlay<K: Ord, V>(
+    plain: impl Iterator<Item = eyre::Result<(K, V)>>,
+    overlay: impl Iterator<Item = eyre::Result<(K, Option<V>)>>,
+) -> impl Iterator<Item = eyre::Result<(K, V)>> {
+    merge_join(plain, overlay).filter_map(|entry| match entry {
+        Ok((key, plain, overlay)) => overlay.unwrap_or(plain).map(|value| Ok((key, value))),
+        Err(e) => Some(Err(e)),
+    })
+}
+
+/// Reth's state at one block: a database transaction for table walks plus a state provider
+/// pinned to that block.
+///
+/// Accounts and storage are streamed from the plain state tables, which hold the state at the
+/// tip. For an earlier block, the changesets above it are unwound into a [`ChangesetOverlay`]
+/// that takes precedence over the plain tables. With an overlay, at any block, bytecodes are
+/// limited to those the accounts at that block reference.
+pub struct RethSource<P> {
+    provider: P,
+    state: StateProviderBox,
+    overlay: Arc<ChangesetOverlay>,
+}
+
+impl<P: DBProvider> RethSource<P> {
+    pub fn new(provider: P, state: StateProviderBox) -> Self {
+        Self {
+            provider,
+            state,
+            overlay: Arc::default(),
+        }
+    }
+
+    /// Unwind the plain state tables with `overlay`, which must have been collected for the
+    /// block `state` points at. The overlay is shared, so sources opened on several
+    /// transactions only collect it once.
+    pub fn with_overlay(mut self, overlay: Arc<ChangesetOverlay>) -> Self {
+        self.overlay = overlay;
+        self
+    }
+}
+
+impl<P: DBProvider> StateSource for RethSource<P> {
+    fn accounts(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<(Address, DbAccountInfo)>> + '_> {
+        let cursor = match self
+            .provider
+            .tx_ref()
+            .cursor_read::<tables::PlainAccountState>()
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Box::new(std::iter::once(Err(e.into()))),
+        };
+        let plain = walk_table(cursor, shard.start_address(), shard)
+            .map(|entry| entry.map_err(eyre::Report::from));
+        Box::new(
+            apply_overlay(plain, self.overlay.accounts(shard))
+                .map(|entry| entry.map(|(address, account)| (address, account.into()))),
+        )
+    }
+
+    fn account(&self, address: Address) -> eyre::Result<Option<DbAccountInfo>> {
+        Ok(self.state.basic_account(&address)?.map(Into::into))
+    }
+
+    fn storage(&self, address: Address) -> eyre::Result<BTreeMap<B256, U256>> {
+        let mut storage = plain_storage(&self.provider, address)?;
+        let overlay = self
+            .overlay
+            .storage
+            .range((address, B256::ZERO)..=(address, B256::repeat_byte(0xff)));
+        for ((_, slot), value) in overlay {
+            storage.insert(*slot, *value);
+        }
+        storage.retain(|_, value| *value != U256::ZERO);
+        Ok(storage)
+    }
+
+    fn storage_entries(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<((Address, B256), U256)>> + '_> {
+        let cursor = match self
+            .provider
+            .tx_ref()
+            .cursor_dup_read::<tables::PlainStorageState>()
+        {
+            Ok(cursor) => cursor,
+            Err(e) => return Box::new(std::iter::once(Err(e.into()))),
+        };
+        let plain = walk_table(cursor, shard.start_address(), shard)
+            .map(|entry| {
+                entry
+                    .map(|(address, entry)| ((address, entry.key), entry.value))
+                    .map_err(eyre::Report::from)
+            })
+            .filter(|entry| !matches!(entry, Ok((_, value)) if *value == U256::ZERO));
+        Box::new(apply_overlay(plain, self.overlay.storage(shard)))
+    }
+
+    /// Read from reth's hashed storage tables, so this relies on the hashing stages having
+    /// caught up with execution.
+    fn storage_root(&self, address: Address) -> eyre::Result<B256> {
+        Ok(self.state.storage_root(address, HashedStorage::default())?)
+    }
+
+    fn bytecode(&self, code_hash: B256) -> eyre::Result<Option<Bytes>> {
+        Ok(self
+            .state
+            .bytecode_by_hash(&code_hash)?
+            .map(|code| code.original_bytes()))
+    }
+
+    fn contracts(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<(B256, Bytes)>> + '_> {
+        let cursor = match self.provider.tx_ref().cursor_read::<tables::Bytecodes>() {
+            Ok(cursor) => cursor,
+            Err(e) => return Box::new(std::iter::once(Err(e.into()))),
+        };
+        let code_hashes = self.overlay.code_hashes.as_ref();
+        Box::new(
+            walk_table(cursor, shard.start_hash(), shard)
+                .filter(move |entry| match (entry, code_hashes) {
+                    (Ok((code_hash, _)), Some(code_hashes)) => code_hashes.contains(code_hash),
+                    _ => true,
+                })
+                .map(|entry| {
+                    entry
+                        .map(|(code_hash, code)| (code_hash, code.original_bytes()))
+                        .map_err(Into::into)
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes at blocks 5, 10 and 20 taking the value from 1 through to 4.
+    fn history() -> History<u64> {
+        History::new(vec![5, 10, 20], vec![1, 2, 3], 4)
+    }
+
+    #[test]
+    fn matching_value_at_block() {
+        assert_eq!(history().divergence(12, &3), HistoryDivergence::Matches);
+        assert_eq!(history().divergence(3, &1), HistoryDivergence::Matches);
+        assert_eq!(history().divergence(25, &4), HistoryDivergence::Matches);
+    }
+
+    #[test]
+    fn diverged_after_last_matching_write() {
+        assert_eq!(
+            history().divergence(12, &2),
+            HistoryDivergence::DivergedAt {
+                block: 10,
+                last_match: Some(5),
+            }
+        );
+        assert_eq!(
+            history().divergence(25, &3),
+            HistoryDivergence::DivergedAt {
+                block: 20,
+                last_match: Some(10),
+            }
+        );
+    }
+
+    #[test]
+    fn diverged_at_first_write_when_value_before_it_matched() {
+        assert_eq!(
+            history().divergence(12, &1),
+            HistoryDivergence::DivergedAt {
+                block: 5,
+                last_match: None,
+            }
+        );
+    }
+
+    #[test]
+    fn writes_after_block_are_ignored() {
+        assert_eq!(
+            history().divergence(12, &4),
+            HistoryDivergence::NeverMatched
+        );
+    }
+
+    #[test]
+    fn history_without_writes() {
+        let history = History::new(Vec::new(), Vec::new(), 7);
+        assert_eq!(history.divergence(12, &7), HistoryDivergence::Matches);
+        assert_eq!(history.divergence(12, &8), HistoryDivergence::NeverMatched);
+    }
+}
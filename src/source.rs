@@ -0,0 +1,165 @@
+use crate::types::DbAccountInfo;
+use alloy_primitives::{Address, Bytes, B256, U256};
+use alloy_trie::root::storage_root_unhashed;
+use std::collections::BTreeMap;
+
+/// A range of keys selected by their leading byte: `start..end`, with `end <= 256`.
+///
+/// Addresses and code hashes are both split this way, so a shard covers the same slice of the
+/// account, storage and bytecode tables of every source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    start: u16,
+    end: u16,
+}
+
+impl Shard {
+    pub const ALL: Self = Self { start: 0, end: 256 };
+
+    /// Split the key space into `count` contiguous shards of near equal width.
+    pub fn split(count: usize) -> Vec<Self> {
+        let count = count.clamp(1, 256) as u16;
+        (0..count)
+            .map(|i| Self {
+                start: i * 256 / count,
+                end: (i + 1) * 256 / count,
+            })
+            .collect()
+    }
+
+    /// Leading byte of the first key in this shard.
+    pub fn start_byte(&self) -> u8 {
+        self.start as u8
+    }
+
+    pub fn start_address(&self) -> Address {
+        let mut address = Address::ZERO;
+        address[0] = self.start_byte();
+        address
+    }
+
+    pub fn start_hash(&self) -> B256 {
+        let mut hash = B256::ZERO;
+        hash[0] = self.start_byte();
+        hash
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        key.first()
+            .is_some_and(|byte| (self.start..self.end).contains(&u16::from(*byte)))
+    }
+}
+
+/// A read-only view of an EVM state that can be diffed against any other.
+///
+/// Implementations only report non-zero storage slots, so two sources agree on a slot exactly
+/// when both report the same value or both omit it.
+pub trait StateSource {
+    /// Accounts of `shard` in ascending address order.
+    fn accounts(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<(Address, DbAccountInfo)>> + '_>;
+
+    fn account(&self, address: Address) -> eyre::Result<Option<DbAccountInfo>>;
+
+    /// Non-zero storage slots of `address`.
+    fn storage(&self, address: Address) -> eyre::Result<BTreeMap<B256, U256>>;
+
+    /// Non-zero storage slots of every account of `shard` in ascending `(address, slot)` order.
+    ///
+    /// The default implementation looks up each account's storage; sources backed by an
+    /// ordered table should stream it instead.
+    fn storage_entries(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<((Address, B256), U256)>> + '_> {
+        Box::new(self.accounts(shard).flat_map(move |account| {
+            let storage = account.and_then(|(address, _)| Ok((address, self.storage(address)?)));
+            match storage {
+                Ok((address, storage)) => storage
+                    .into_iter()
+                    .map(|(slot, value)| Ok(((address, slot), value)))
+                    .collect::<Vec<_>>(),
+                Err(e) => vec![Err(e)],
+            }
+        }))
+    }
+
+    /// Merkle-Patricia root of the storage of `address`.
+    ///
+    /// The default implementation hashes the slots returned by [`StateSource::storage`];
+    /// sources that keep a storage trie should read its root instead.
+    fn storage_root(&self, address: Address) -> eyre::Result<B256> {
+        Ok(storage_root_unhashed(self.storage(address)?))
+    }
+
+    /// Raw bytecode stored under `code_hash`.
+    fn bytecode(&self, code_hash: B256) -> eyre::Result<Option<Bytes>>;
+
+    /// Bytecodes of `shard` in ascending code hash order.
+    fn contracts(&self, shard: Shard)
+        -> Box<dyn Iterator<Item = eyre::Result<(B256, Bytes)>> + '_>;
+}
+
+/// Every account of `source` in address order, with its non-zero slots.
+///
+/// Storage is streamed alongside the accounts, so only one account's slots are held at a time.
+/// Slots of addresses without an account are skipped.
+pub fn accounts_with_storage(
+    source: &dyn StateSource,
+) -> impl Iterator<Item = eyre::Result<(Address, DbAccountInfo, Vec<(B256, U256)>)>> + '_ {
+    let mut storage = source.storage_entries(Shard::ALL).peekable();
+    source.accounts(Shard::ALL).map(move |account| {
+        let (address, info) = account?;
+        let mut slots = Vec::new();
+        while let Some(entry) =
+            storage.next_if(|entry| !matches!(entry, Ok(((next, _), _)) if *next > address))
+        {
+            let ((slot_address, slot), value) = entry?;
+            if slot_address == address {
+                slots.push((slot, value));
+            }
+        }
+        Ok((address, info, slots))
+    })
+}
+
+impl<T: StateSource + ?Sized> StateSource for &T {
+    fn accounts(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<(Address, DbAccountInfo)>> + '_> {
+        (**self).accounts(shard)
+    }
+
+    fn account(&self, address: Address) -> eyre::Result<Option<DbAccountInfo>> {
+        (**self).account(address)
+    }
+
+    fn storage(&self, address: Address) -> eyre::Result<BTreeMap<B256, U256>> {
+        (**self).storage(address)
+    }
+
+    fn storage_entries(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<((Address, B256), U256)>> + '_> {
+        (**self).storage_entries(shard)
+    }
+
+    fn storage_root(&self, address: Address) -> eyre::Result<B256> {
+        (**self).storage_root(address)
+    }
+
+    fn bytecode(&self, code_hash: B256) -> eyre::Result<Option<Bytes>> {
+        (**self).bytecode(code_hash)
+    }
+
+    fn contracts(
+        &self,
+        shard: Shard,
+    ) -> Box<dyn Iterator<Item = eyre::Result<(B256, Bytes)>> + '_> {
+        (**self).contracts(shard)
+    }
+}
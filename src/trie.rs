@@ -0,0 +1,51 @@
+use crate::source::{accounts_with_storage, StateSource};
+use alloy_primitives::{keccak256, B256};
+use alloy_trie::root::{state_root_unsorted, storage_root_unhashed};
+use alloy_trie::TrieAccount;
+
+/// Merkle-Patricia root of every account of `source`, as committed to by a block header.
+///
+/// Only one account's slots are held at a time; the hashed accounts themselves are collected
+/// and sorted before the root is built. Empty accounts without storage are left out, as the
+/// diff treats them as absent, and so are slots of addresses without an account.
+pub fn state_root(source: &dyn StateSource) -> eyre::Result<B256> {
+    let mut accounts = Vec::new();
+    for account in accounts_with_storage(source) {
+        let (address, info, slots) = account?;
+        if info.is_empty() && slots.is_empty() {
+            continue;
+        }
+        accounts.push((
+            keccak256(address),
+            TrieAccount {
+                nonce: info.nonce,
+                balance: info.balance,
+                storage_root: storage_root_unhashed(slots),
+                code_hash: info.code_hash,
+            },
+        ));
+    }
+    Ok(state_root_unsorted(accounts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abci::InMemorySource;
+    use crate::types::DbAccountInfo;
+    use alloy_primitives::{Address, U256};
+
+    #[test]
+    fn empty_accounts_do_not_change_the_root() {
+        let info = DbAccountInfo {
+            balance: U256::from(1),
+            ..Default::default()
+        };
+        let state = InMemorySource::default().with_account(Address::repeat_byte(1), info, &[]);
+        let touched =
+            state
+                .clone()
+                .with_account(Address::repeat_byte(2), DbAccountInfo::default(), &[]);
+        assert_eq!(state_root(&touched).unwrap(), state_root(&state).unwrap());
+    }
+}
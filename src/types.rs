@@ -16,6 +16,14 @@ pub enum Bytecode {
 }
 
 impl Bytecode {
+    /// Wrap raw code, recognizing EIP-7702 delegations by their `0xef0100` designator.
+    pub fn new_raw(bytes: Bytes) -> Self {
+        match Eip7702Bytecode::new_raw(bytes.clone()) {
+            Ok(bytecode) => Self::Eip7702(bytecode),
+            Err(_) => Self::LegacyRaw(bytes),
+        }
+    }
+
     pub fn original_bytes(&self) -> Bytes {
         match self {
             Self::Eip7702(bytecode) => bytecode.raw().clone(),
@@ -32,9 +40,12 @@ pub struct BlockAndReceipts {
     #[serde(default)]
     pub system_txs: Vec<SystemTx>,
     #[serde(default)]
-    pub read_precompile_calls: Vec<(Address, Vec<(ReadPrecompileInput, ReadPrecompileResult)>)>,
+    pub read_precompile_calls: ReadPrecompileCalls,
 }
 
+/// Results of read precompile calls made by a block, grouped by precompile address.
+pub type ReadPrecompileCalls = Vec<(Address, Vec<(ReadPrecompileInput, ReadPrecompileResult)>)>;
+
 /// Sealed full block composed of the block's header and body.
 ///
 /// This type uses lazy sealing to avoid hashing the header until it is needed, see also
@@ -51,16 +62,24 @@ pub enum EvmBlock {
     Reth115(SealedBlock),
 }
 
+impl EvmBlock {
+    pub fn header(&self) -> &SealedHeader<Header> {
+        match self {
+            Self::Reth115(block) => &block.header,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LegacyReceipt {
-    tx_type: LegacyTxType,
-    success: bool,
-    cumulative_gas_used: u64,
-    logs: Vec<Log>,
+    pub tx_type: LegacyTxType,
+    pub success: bool,
+    pub cumulative_gas_used: u64,
+    pub logs: Vec<Log>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-enum LegacyTxType {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LegacyTxType {
     Legacy = 0,
     Eip2930 = 1,
     Eip1559 = 2,
@@ -80,7 +99,7 @@ pub struct ReadPrecompileInput {
     pub gas_limit: u64,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ReadPrecompileResult {
     Ok { gas_used: u64, bytes: Bytes },
     OutOfGas,
@@ -122,6 +141,15 @@ pub struct EvmState {
     pub block_hashes: Vec<(U256, B256)>,
 }
 
+impl EvmState {
+    /// The BLOCKHASH window as block numbers and hashes.
+    pub fn block_hashes(&self) -> impl Iterator<Item = (u64, B256)> + '_ {
+        self.block_hashes
+            .iter()
+            .map(|(number, hash)| (number.saturating_to(), *hash))
+    }
+}
+
 #[derive(Deserialize)]
 pub enum EvmDb {
     InMemory {
@@ -139,7 +167,7 @@ pub struct DbAccount {
     pub storage: Vec<(U256, U256)>,
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub struct DbAccountInfo {
     #[serde(rename = "b", alias = "balance", default)]
     pub balance: U256,
@@ -149,6 +177,14 @@ pub struct DbAccountInfo {
     pub code_hash: B256,
 }
 
+impl DbAccountInfo {
+    /// An account with zero balance, zero nonce and no code, i.e. indistinguishable from an
+    /// absent one.
+    pub fn is_empty(&self) -> bool {
+        self.balance == U256::ZERO && self.nonce == 0 && self.code_hash == KECCAK_EMPTY
+    }
+}
+
 impl Default for DbAccountInfo {
     fn default() -> Self {
         Self {